@@ -1,24 +1,212 @@
+use clap::Parser;
 use log::*;
 use std::env;
+use std::net::IpAddr;
+use std::path::PathBuf;
 
+use switchboard_sfu::config::IceServerConfig;
+use switchboard_sfu::signal::tls::TlsConfig;
 use switchboard_sfu::*;
 
+/// switchboard SFU
+///
+/// Settings come from the config file, then environment variables, then flags
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    /// TOML config file
+    #[arg(short, long, env = "SWITCHBOARD_CONFIG")]
+    config: Option<PathBuf>,
+    /// Address to listen on, can be repeated
+    #[arg(long, env = "SWITCHBOARD_LISTEN", value_delimiter = ',')]
+    listen: Vec<String>,
+    /// Listen address (same as --listen)
+    #[arg(hide = true)]
+    addr: Option<String>,
+    /// Log filter used when RUST_LOG isn't set
+    #[arg(long, env = "SWITCHBOARD_LOG")]
+    log: Option<String>,
+
+    /// PEM certificate chain, serves wss:// together with --tls-key
+    #[arg(long, env = "SWITCHBOARD_TLS_CERT", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key of the certificate
+    #[arg(long, env = "SWITCHBOARD_TLS_KEY", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// STUN/TURN server url, can be repeated (replaces the configured servers)
+    #[arg(
+        long = "ice-server",
+        env = "SWITCHBOARD_ICE_SERVERS",
+        value_delimiter = ','
+    )]
+    ice_servers: Vec<String>,
+    /// Public IP advertised as a host candidate, can be repeated
+    #[arg(long = "nat-ip", env = "SWITCHBOARD_NAT_IPS", value_delimiter = ',')]
+    nat_ips: Vec<String>,
+    /// Detect the public IP with STUN and advertise it instead of the local IPs
+    #[arg(long, env = "SWITCHBOARD_DETECT_NAT")]
+    detect_nat: bool,
+    /// STUN server used to detect the public IP, can be repeated
+    #[arg(
+        long = "detect-nat-stun-server",
+        env = "SWITCHBOARD_DETECT_NAT_STUN_SERVERS",
+        value_delimiter = ','
+    )]
+    detect_nat_stun_servers: Vec<String>,
+    /// Lowest UDP port used by peer connections
+    #[arg(long, env = "SWITCHBOARD_UDP_PORT_MIN")]
+    udp_port_min: Option<u16>,
+    /// Highest UDP port used by peer connections
+    #[arg(long, env = "SWITCHBOARD_UDP_PORT_MAX")]
+    udp_port_max: Option<u16>,
+    /// Single UDP port for all ICE traffic, instead of the port range
+    #[arg(long, env = "SWITCHBOARD_UDP_MUX_PORT")]
+    udp_mux_port: Option<u16>,
+    /// TCP port for ICE-TCP candidates, for clients that can't use UDP (needs the udp mux port)
+    #[arg(long, env = "SWITCHBOARD_ICE_TCP_PORT")]
+    ice_tcp_port: Option<u16>,
+
+    /// UDP port of the embedded STUN/TURN server
+    #[arg(long, env = "SWITCHBOARD_TURN_UDP_PORT")]
+    turn_udp_port: Option<u16>,
+    /// TCP port of the embedded TURN server
+    #[arg(long, env = "SWITCHBOARD_TURN_TCP_PORT")]
+    turn_tcp_port: Option<u16>,
+    /// IP clients reach the TURN server at, the first NAT IP if unset
+    #[arg(long, env = "SWITCHBOARD_TURN_PUBLIC_IP")]
+    turn_public_ip: Option<IpAddr>,
+    /// TURN username handed to clients
+    #[arg(long, env = "SWITCHBOARD_TURN_USERNAME")]
+    turn_username: Option<String>,
+    /// TURN password handed to clients
+    #[arg(long, env = "SWITCHBOARD_TURN_PASSWORD", hide_env_values = true)]
+    turn_password: Option<String>,
+    /// TURN REST API shared secret, issues credentials per join instead of the username and
+    /// password
+    #[arg(long, env = "SWITCHBOARD_TURN_SECRET", hide_env_values = true)]
+    turn_secret: Option<String>,
+
+    /// Codec to negotiate, can be repeated (all codecs if none)
+    #[arg(long = "codec", env = "SWITCHBOARD_CODECS", value_delimiter = ',')]
+    codecs: Vec<String>,
+
+    /// Sessions allowed at once
+    #[arg(long, env = "SWITCHBOARD_MAX_SESSIONS")]
+    max_sessions: Option<usize>,
+    /// Peers allowed in one session
+    #[arg(long, env = "SWITCHBOARD_MAX_PEERS_PER_SESSION")]
+    max_peers_per_session: Option<usize>,
+
+    /// Seconds sessions get to empty on shutdown before their peers are closed
+    #[arg(long, env = "SWITCHBOARD_DRAIN_TIMEOUT_SECS")]
+    drain_timeout_secs: Option<u64>,
+    /// Seconds a disconnected peer can resume its connection, 0 disables resuming
+    #[arg(long, env = "SWITCHBOARD_RESUME_GRACE_SECS")]
+    resume_grace_secs: Option<u64>,
+
+    /// HS256 secret of join tokens
+    #[arg(long, env = "SWITCHBOARD_JWT_SECRET", hide_env_values = true)]
+    jwt_secret: Option<String>,
+    /// PEM public key file of RS256 join tokens
+    #[arg(long, env = "SWITCHBOARD_JWT_PUBLIC_KEY")]
+    jwt_public_key: Option<PathBuf>,
+}
+
+impl Args {
+    /// Overrides the config with every setting given on the command line or environment
+    fn apply(self, cfg: &mut ServerConfig) {
+        let mut listen = self.listen;
+        listen.extend(self.addr);
+        if !listen.is_empty() {
+            cfg.listen = listen;
+        }
+        if let Some(log) = self.log {
+            cfg.log = log;
+        }
+
+        if let (Some(cert), Some(key)) = (self.tls_cert, self.tls_key) {
+            cfg.tls = Some(TlsConfig::new(cert, key));
+        }
+
+        if !self.ice_servers.is_empty() {
+            cfg.ice.servers = self
+                .ice_servers
+                .into_iter()
+                .map(|url| IceServerConfig {
+                    urls: vec![url],
+                    ..Default::default()
+                })
+                .collect();
+        }
+        if !self.nat_ips.is_empty() {
+            cfg.ice.nat_ips = self.nat_ips;
+        }
+        if self.detect_nat {
+            cfg.ice.detect_nat.enabled = true;
+        }
+        if !self.detect_nat_stun_servers.is_empty() {
+            cfg.ice.detect_nat.stun_servers = self.detect_nat_stun_servers;
+        }
+        cfg.ice.udp_port_min = self.udp_port_min.or(cfg.ice.udp_port_min);
+        cfg.ice.udp_port_max = self.udp_port_max.or(cfg.ice.udp_port_max);
+        cfg.ice.udp_mux_port = self.udp_mux_port.or(cfg.ice.udp_mux_port);
+        cfg.ice.tcp_port = self.ice_tcp_port.or(cfg.ice.tcp_port);
+
+        cfg.turn.udp_port = self.turn_udp_port.or(cfg.turn.udp_port);
+        cfg.turn.tcp_port = self.turn_tcp_port.or(cfg.turn.tcp_port);
+        cfg.turn.public_ip = self.turn_public_ip.or(cfg.turn.public_ip);
+        if let Some(username) = self.turn_username {
+            cfg.turn.username = username;
+        }
+        if let Some(password) = self.turn_password {
+            cfg.turn.password = password;
+        }
+        cfg.turn.secret = self.turn_secret.or(cfg.turn.secret.take());
+
+        if !self.codecs.is_empty() {
+            cfg.codecs = self.codecs;
+        }
+
+        cfg.limits.max_sessions = self.max_sessions.or(cfg.limits.max_sessions);
+        cfg.limits.max_peers_per_session = self
+            .max_peers_per_session
+            .or(cfg.limits.max_peers_per_session);
+
+        if let Some(drain_timeout_secs) = self.drain_timeout_secs {
+            cfg.shutdown.drain_timeout_secs = drain_timeout_secs;
+        }
+        if let Some(resume_grace_secs) = self.resume_grace_secs {
+            cfg.resume.grace_secs = resume_grace_secs;
+        }
+
+        cfg.auth.jwt_secret = self.jwt_secret.or(cfg.auth.jwt_secret.take());
+        cfg.auth.jwt_public_key = self.jwt_public_key.or(cfg.auth.jwt_public_key.take());
+    }
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
+    let mut config = match &args.config {
+        Some(path) => ServerConfig::from_file(path)?,
+        None => ServerConfig::default(),
+    };
+    args.apply(&mut config);
+
     if env::var_os("RUST_LOG").is_none() {
-        env::set_var("RUST_LOG", "switchboard=info");
+        env::set_var("RUST_LOG", &config.log);
     }
     pretty_env_logger::init();
 
-    let extip = switchboard_sfu::extip::resolve_external_ip_maps().await?;
+    config.validate()?;
 
-    debug!("found mappings: {:?}", extip);
-
-    let addr = env::args()
-        .nth(1)
-        .unwrap_or_else(|| "127.0.0.1:7000".to_string());
+    if config.auth.jwt_secret.is_none() && config.auth.jwt_public_key.is_none() {
+        warn!("no join token keys configured, authentication is disabled");
+    }
 
-    signal::run_server(&addr).await;
+    signal::run_server(config).await?;
 
     Ok(())
 }
@@ -0,0 +1,534 @@
+use log::*;
+use serde::Deserialize;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+use webrtc::ice::network_type::NetworkType;
+use webrtc::ice::udp_mux::{UDPMux, UDPMuxDefault, UDPMuxParams};
+use webrtc::ice::udp_network::{EphemeralUDP, UDPNetwork};
+use webrtc::ice::url::Url;
+use webrtc::ice_transport::ice_candidate_type::RTCIceCandidateType;
+use webrtc::ice_transport::ice_server::RTCIceServer;
+
+use crate::sfu::coordinator::CoordinatorConfig;
+use crate::sfu::mediaengine::CODEC_NAMES;
+use crate::sfu::peer::PeerConfig;
+use crate::sfu::session::SessionConfig;
+use crate::sfu::tcp_mux::TcpMux;
+use crate::signal::auth::TokenVerifier;
+use crate::signal::tls::TlsConfig;
+use crate::turn::{IceServers, TurnConfig};
+use crate::{extip, turn, Error, Result};
+
+/// Configuration of the switchboard server, usually read from a TOML file
+/// Every field has a default, so a file only lists what it changes
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Addresses the signal server listens on
+    pub listen: Vec<String>,
+    /// Log filter used when RUST_LOG isn't set
+    pub log: String,
+    /// Serve wss:// with this certificate instead of ws://
+    pub tls: Option<TlsConfig>,
+    pub ice: IceConfig,
+    /// Codec names to negotiate (opus, g722, pcmu, pcma, vp8, vp9, h264), all if empty
+    pub codecs: Vec<String>,
+    pub limits: LimitsConfig,
+    pub auth: AuthConfig,
+    pub shutdown: ShutdownConfig,
+    pub resume: ResumeConfig,
+    pub turn: TurnConfig,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            listen: vec!["127.0.0.1:7000".to_owned()],
+            log: "switchboard=info".to_owned(),
+            tls: None,
+            ice: IceConfig::default(),
+            codecs: vec![],
+            limits: LimitsConfig::default(),
+            auth: AuthConfig::default(),
+            shutdown: ShutdownConfig::default(),
+            resume: ResumeConfig::default(),
+            turn: TurnConfig::default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct IceConfig {
+    /// STUN/TURN servers used by the server side peer connections
+    pub servers: Vec<IceServerConfig>,
+    /// Public IPs advertised as host candidates (NAT 1:1), for servers behind a static NAT
+    /// Either a single IP per address family, or ext/local mappings
+    pub nat_ips: Vec<String>,
+    pub detect_nat: DetectNatConfig,
+    /// Range of UDP ports peer connections bind, any port if unset
+    pub udp_port_min: Option<u16>,
+    pub udp_port_max: Option<u16>,
+    /// Single UDP port shared by the ICE traffic of every peer connection, instead of a port
+    /// per connection
+    pub udp_mux_port: Option<u16>,
+    /// Port of an ICE-TCP listener for clients that can't use UDP, its passive candidates are
+    /// added to both peer connections. Needs udp_mux_port, the TCP streams go through the mux.
+    pub tcp_port: Option<u16>,
+    /// Seconds the TURN REST credentials issued with each join stay valid
+    pub credential_ttl_secs: u64,
+}
+
+impl Default for IceConfig {
+    fn default() -> IceConfig {
+        IceConfig {
+            servers: vec![IceServerConfig {
+                urls: vec!["stun:stun.l.google.com:19302".to_owned()],
+                ..Default::default()
+            }],
+            nat_ips: vec![],
+            detect_nat: DetectNatConfig::default(),
+            udp_port_min: None,
+            udp_port_max: None,
+            udp_mux_port: None,
+            tcp_port: None,
+            credential_ttl_secs: 86400,
+        }
+    }
+}
+
+/// Finds the public IP with STUN at startup and uses it as the NAT 1:1 IP of every local
+/// address, when nat_ips is empty
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct DetectNatConfig {
+    pub enabled: bool,
+    pub stun_servers: Vec<String>,
+    /// Seconds to wait for a STUN answer, host candidates stay private without one
+    pub timeout_secs: u64,
+}
+
+impl Default for DetectNatConfig {
+    fn default() -> DetectNatConfig {
+        DetectNatConfig {
+            enabled: false,
+            stun_servers: vec!["stun:stun.l.google.com:19302".to_owned()],
+            timeout_secs: 5,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct IceServerConfig {
+    pub urls: Vec<String>,
+    pub username: String,
+    pub credential: String,
+    /// TURN REST API shared secret, time-limited credentials are then issued for every join
+    /// instead of username/credential
+    pub secret: Option<String>,
+}
+
+impl IceServerConfig {
+    /// RTCIceServer with the configured credentials, or TURN REST credentials for identity
+    pub fn issue(&self, identity: &str, ttl: Duration) -> RTCIceServer {
+        let (username, credential) = match &self.secret {
+            Some(secret) => turn::rest_credentials(secret, identity, ttl),
+            None => (self.username.clone(), self.credential.clone()),
+        };
+        RTCIceServer {
+            urls: self.urls.clone(),
+            username,
+            credential,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Sessions allowed at once, unlimited if unset
+    pub max_sessions: Option<usize>,
+    /// Peers allowed in one session, unlimited if unset
+    pub max_peers_per_session: Option<usize>,
+}
+
+/// Keys join tokens are verified with, authentication is disabled without any
+#[derive(Deserialize, Clone, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Shared secret of HS256 tokens
+    pub jwt_secret: Option<String>,
+    /// PEM file with the public key of RS256 tokens
+    pub jwt_public_key: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    /// Seconds sessions get to empty on SIGINT/SIGTERM before their peers are closed
+    pub drain_timeout_secs: u64,
+    /// Reconnect hint sent to clients in the server_shutdown notification
+    pub reconnect_after_ms: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> ShutdownConfig {
+        ShutdownConfig {
+            drain_timeout_secs: 30,
+            reconnect_after_ms: 1000,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResumeConfig {
+    /// Seconds a peer stays in its session after its connection drops, waiting for a resume
+    /// (0 disables resuming)
+    pub grace_secs: u64,
+}
+
+impl Default for ResumeConfig {
+    fn default() -> ResumeConfig {
+        ResumeConfig { grace_secs: 30 }
+    }
+}
+
+impl ServerConfig {
+    /// Reads a TOML config file, fields missing from the file keep their defaults
+    pub fn from_file(path: impl AsRef<Path>) -> Result<ServerConfig> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)
+            .map_err(|err| Error::Config(format!("couldn't read {}: {}", path.display(), err)))?;
+        toml::from_str(&contents)
+            .map_err(|err| Error::Config(format!("{}: {}", path.display(), err)))
+    }
+
+    /// Checks the whole config, so mistakes are reported at startup
+    pub fn validate(&self) -> Result<()> {
+        if self.listen.is_empty() {
+            return Err(config_error("listen needs at least one address"));
+        }
+        for addr in &self.listen {
+            addr.parse::<SocketAddr>()
+                .map_err(|err| config_error(format!("listen address {:?}: {}", addr, err)))?;
+        }
+
+        if let Some(tls) = &self.tls {
+            for path in [&tls.cert_path, &tls.key_path] {
+                if !path.is_file() {
+                    return Err(config_error(format!(
+                        "tls file {} not found",
+                        path.display()
+                    )));
+                }
+            }
+        }
+
+        for server in &self.ice.servers {
+            if server.urls.is_empty() {
+                return Err(config_error("ice server without urls"));
+            }
+            for url in &server.urls {
+                Url::parse_url(url)
+                    .map_err(|err| config_error(format!("ice server url {:?}: {}", url, err)))?;
+            }
+            if server.secret.as_deref() == Some("") {
+                return Err(config_error("ice server secret is empty"));
+            }
+        }
+        for ip in &self.ice.nat_ips {
+            validate_nat_ip(ip)?;
+        }
+        if self.ice.detect_nat.enabled {
+            if self.ice.detect_nat.stun_servers.is_empty() {
+                return Err(config_error("detect_nat needs at least one stun server"));
+            }
+            for url in &self.ice.detect_nat.stun_servers {
+                Url::parse_url(url)
+                    .map_err(|err| config_error(format!("stun server {:?}: {}", url, err)))?;
+            }
+        }
+        self.ephemeral_udp()?;
+        if let Some(port) = self.ice.udp_mux_port {
+            if port == 0 {
+                return Err(config_error("udp_mux_port must not be 0"));
+            }
+            if self.ice.udp_port_min.is_some() || self.ice.udp_port_max.is_some() {
+                return Err(config_error(
+                    "udp_mux_port can't be combined with a udp port range",
+                ));
+            }
+        }
+        if let Some(port) = self.ice.tcp_port {
+            if port == 0 {
+                return Err(config_error("ice tcp_port must not be 0"));
+            }
+            if self.ice.udp_mux_port.is_none() {
+                return Err(config_error("ice tcp_port needs udp_mux_port"));
+            }
+        }
+
+        if self.turn.enabled() {
+            match &self.turn.secret {
+                Some(secret) if secret.is_empty() => {
+                    return Err(config_error("turn secret is empty"))
+                }
+                Some(_) => {}
+                None if self.turn.username.is_empty() || self.turn.password.is_empty() => {
+                    return Err(config_error(
+                        "turn needs a secret or a username and password",
+                    ))
+                }
+                None => {}
+            }
+            match (self.turn.relay_port_min, self.turn.relay_port_max) {
+                (None, None) => {}
+                (Some(min), Some(max)) if min > 0 && min <= max => {}
+                _ => return Err(config_error("invalid turn relay port range")),
+            }
+        }
+
+        for codec in &self.codecs {
+            if !CODEC_NAMES
+                .iter()
+                .any(|name| name.eq_ignore_ascii_case(codec))
+            {
+                return Err(config_error(format!(
+                    "unknown codec {:?}, expected one of {}",
+                    codec,
+                    CODEC_NAMES.join(", ")
+                )));
+            }
+        }
+
+        if self.limits.max_sessions == Some(0) || self.limits.max_peers_per_session == Some(0) {
+            return Err(config_error("limits must be at least 1"));
+        }
+
+        self.token_verifier()?;
+        Ok(())
+    }
+
+    /// Base PeerConfig of every peer, join requests fill in the per peer fields and the ICE
+    /// servers (see ice_servers)
+    /// With udp_mux_port this binds the shared socket, so it needs a tokio runtime and is
+    /// called once
+    pub fn peer_config(&self) -> Result<PeerConfig> {
+        let mut cfg = PeerConfig::default();
+
+        cfg.rtc_config.ice_servers = vec![];
+        if !self.ice.nat_ips.is_empty() {
+            cfg.setting_engine
+                .set_nat_1to1_ips(self.ice.nat_ips.clone(), RTCIceCandidateType::Host);
+        }
+        if let Some(port) = self.ice.udp_mux_port {
+            let udp_mux = bind_udp_mux(port)?;
+            let mux: Arc<dyn UDPMux + Send + Sync> = match self.ice.tcp_port {
+                Some(tcp_port) => {
+                    cfg.ice_tcp_port = Some(tcp_port);
+                    TcpMux::new(udp_mux, bind_tcp_mux(tcp_port)?)
+                }
+                None => udp_mux,
+            };
+            cfg.setting_engine.set_udp_network(UDPNetwork::Muxed(mux));
+            // the mux socket is IPv4, IPv6 candidates would share it and drop its packets
+            cfg.setting_engine
+                .set_network_types(vec![NetworkType::Udp4]);
+        } else if let Some(ephemeral) = self.ephemeral_udp()? {
+            cfg.setting_engine
+                .set_udp_network(UDPNetwork::Ephemeral(ephemeral));
+        }
+        cfg.codecs = self.codecs.clone();
+
+        Ok(cfg)
+    }
+
+    /// ICE servers issued to the peers of every join
+    pub fn ice_servers(&self) -> IceServers {
+        IceServers::new(
+            self.ice.servers.clone(),
+            Duration::from_secs(self.ice.credential_ttl_secs),
+        )
+    }
+
+    /// Fills in nat_ips with the mappings found by detect_nat, when enabled and no IPs are
+    /// configured. Without an answer from the STUN servers host candidates stay private.
+    pub async fn detect_nat_ips(&mut self) {
+        let detect = &self.ice.detect_nat;
+        if !detect.enabled || !self.ice.nat_ips.is_empty() {
+            return;
+        }
+
+        let timeout = Duration::from_secs(detect.timeout_secs);
+        match extip::resolve_external_ip_maps(&detect.stun_servers, timeout).await {
+            Ok(maps) => {
+                info!("detected nat 1:1 mappings: {:?}", maps);
+                self.ice.nat_ips = maps;
+            }
+            Err(err) => warn!("nat detection failed, advertising local ips: {}", err),
+        }
+    }
+
+    /// IP the TURN server is reached at, its public_ip or else the external IP of the first
+    /// NAT 1:1 entry
+    pub fn turn_public_ip(&self) -> Result<IpAddr> {
+        if let Some(ip) = self.turn.public_ip {
+            return Ok(ip);
+        }
+        self.ice
+            .nat_ips
+            .first()
+            .and_then(|entry| entry.split('/').next()?.parse().ok())
+            .ok_or_else(|| config_error("turn needs a public_ip (or nat ips to take it from)"))
+    }
+
+    pub fn coordinator_config(&self) -> CoordinatorConfig {
+        CoordinatorConfig {
+            max_sessions: self.limits.max_sessions,
+            session: SessionConfig {
+                max_peers: self.limits.max_peers_per_session,
+            },
+        }
+    }
+
+    /// TokenVerifier for the configured keys, None if authentication is disabled
+    pub fn token_verifier(&self) -> Result<Option<TokenVerifier>> {
+        let mut verifier = None;
+
+        if let Some(secret) = &self.auth.jwt_secret {
+            if secret.is_empty() {
+                return Err(config_error("jwt_secret is empty"));
+            }
+            verifier = Some(TokenVerifier::new().with_hs256_secret(secret.as_bytes()));
+        }
+        if let Some(path) = &self.auth.jwt_public_key {
+            let pem = std::fs::read(path).map_err(|err| {
+                config_error(format!("couldn't read {}: {}", path.display(), err))
+            })?;
+            let with_key = verifier.unwrap_or_default().with_rs256_public_key(&pem);
+            verifier = Some(with_key.map_err(|err| match err {
+                Error::Config(message) => {
+                    config_error(format!("jwt_public_key {}: {}", path.display(), message))
+                }
+                err => err,
+            })?);
+        }
+
+        Ok(verifier)
+    }
+
+    fn ephemeral_udp(&self) -> Result<Option<EphemeralUDP>> {
+        match (self.ice.udp_port_min, self.ice.udp_port_max) {
+            (None, None) => Ok(None),
+            (Some(min), Some(max)) if min > 0 && min <= max => EphemeralUDP::new(min, max)
+                .map(Some)
+                .map_err(|err| config_error(format!("udp port range: {}", err))),
+            (Some(min), Some(max)) => Err(config_error(format!(
+                "invalid udp port range {}-{}",
+                min, max
+            ))),
+            _ => Err(config_error(
+                "udp_port_min and udp_port_max must be set together",
+            )),
+        }
+    }
+}
+
+/// Binds the UDP socket every peer connection shares, on all interfaces
+fn bind_udp_mux(port: u16) -> Result<Arc<UDPMuxDefault>> {
+    let bind_error = |err| config_error(format!("couldn't bind udp mux port {}: {}", port, err));
+
+    let socket = std::net::UdpSocket::bind(("0.0.0.0", port)).map_err(bind_error)?;
+    socket.set_nonblocking(true).map_err(bind_error)?;
+    let socket = tokio::net::UdpSocket::from_std(socket).map_err(bind_error)?;
+
+    info!("ice traffic muxed on udp port {}", port);
+    Ok(UDPMuxDefault::new(UDPMuxParams::new(socket)))
+}
+
+/// Binds the ICE-TCP listener every peer connection shares, on all interfaces
+fn bind_tcp_mux(port: u16) -> Result<TcpListener> {
+    let bind_error = |err| config_error(format!("couldn't bind ice tcp port {}: {}", port, err));
+
+    let listener = std::net::TcpListener::bind(("0.0.0.0", port)).map_err(bind_error)?;
+    listener.set_nonblocking(true).map_err(bind_error)?;
+    let listener = TcpListener::from_std(listener).map_err(bind_error)?;
+
+    info!("ice tcp candidates on port {}", port);
+    Ok(listener)
+}
+
+/// Checks a NAT 1:1 entry, an IP or an ext/local pair of the same address family
+fn validate_nat_ip(entry: &str) -> Result<()> {
+    let parse = |ip: &str| {
+        ip.parse::<IpAddr>()
+            .map_err(|err| config_error(format!("nat ip {:?}: {}", entry, err)))
+    };
+
+    match entry.split_once('/') {
+        None => parse(entry).map(|_| ()),
+        Some((ext, local)) => {
+            if parse(ext)?.is_ipv4() != parse(local)?.is_ipv4() {
+                return Err(config_error(format!(
+                    "nat ip {:?} maps between address families",
+                    entry
+                )));
+            }
+            Ok(())
+        }
+    }
+}
+
+fn config_error(message: impl Into<String>) -> Error {
+    Error::Config(message.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_example_config() {
+        let cfg: ServerConfig =
+            toml::from_str(include_str!("../../switchboard.example.toml")).unwrap();
+        cfg.validate().unwrap();
+
+        assert_eq!(cfg.listen, vec!["0.0.0.0:7000".to_owned()]);
+        assert_eq!(cfg.ice.servers.len(), 1);
+    }
+
+    #[test]
+    fn rejects_invalid_config() {
+        let invalid = [
+            "listen = []",
+            "listen = [\"localhost\"]",
+            "codecs = [\"av1\"]",
+            "[ice]\nnat_ips = [\"not an ip\"]",
+            "[ice]\nnat_ips = [\"203.0.113.1/fe80::1\"]",
+            "[ice.detect_nat]\nenabled = true\nstun_servers = []",
+            "[ice]\nudp_port_min = 50000",
+            "[ice]\nudp_port_min = 50100\nudp_port_max = 50000",
+            "[ice]\nudp_mux_port = 50000\nudp_port_min = 50000\nudp_port_max = 50100",
+            "[ice]\ntcp_port = 50000",
+            "[ice]\nudp_mux_port = 50000\ntcp_port = 0",
+            "[[ice.servers]]\nurls = [\"http://example.com\"]",
+            "[turn]\nudp_port = 3478",
+            "[turn]\nudp_port = 3478\nsecret = \"\"",
+            "[[ice.servers]]\nurls = [\"turn:example.com\"]\nsecret = \"\"",
+            "[turn]\ntcp_port = 3478\nusername = \"u\"\npassword = \"p\"\nrelay_port_min = 50000",
+            "[limits]\nmax_sessions = 0",
+        ];
+
+        for toml in invalid {
+            let cfg: ServerConfig = toml::from_str(toml).unwrap();
+            assert!(cfg.validate().is_err(), "accepted {:?}", toml);
+        }
+        assert!(toml::from_str::<ServerConfig>("unknown = 1").is_err());
+    }
+}
@@ -0,0 +1,47 @@
+use crate::sfu::peer;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Error returned by switchboard's public APIs
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// Creating, configuring or closing a peer connection failed
+    #[error("peer connection error: {0}")]
+    PeerConnection(#[source] webrtc::Error),
+    /// Setting or creating a session description failed (eg. a bad SDP)
+    #[error("negotiation failed: {0}")]
+    Negotiation(#[source] webrtc::Error),
+    /// Gathering or adding ICE candidates failed
+    #[error("ICE error: {0}")]
+    Ice(#[source] webrtc::Error),
+    /// A track couldn't be routed between peers
+    #[error("routing error: {0}")]
+    Routing(String),
+    /// The signaling connection or session failed
+    #[error("signaling error: {0}")]
+    Signaling(String),
+    /// A join token is missing, invalid or for another session
+    #[error("unauthorized: {0}")]
+    Unauthorized(String),
+    /// The peer lacks the permission for the request
+    #[error("forbidden: {0}")]
+    Forbidden(String),
+    /// A session or server limit was reached
+    #[error("capacity exceeded: {0}")]
+    CapacityExceeded(String),
+    /// The configuration is invalid
+    #[error("invalid config: {0}")]
+    Config(String),
+    #[error("peer id={0} not found")]
+    PeerNotFound(peer::Id),
+    #[error("peer id={0} already exists")]
+    PeerExists(peer::Id),
+    /// The TLS certificate or key couldn't be loaded
+    #[error("tls error: {0}")]
+    Tls(String),
+    /// The embedded TURN server couldn't start
+    #[error("turn server error: {0}")]
+    Turn(String),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+}
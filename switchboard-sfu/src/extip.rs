@@ -1,6 +1,7 @@
-use anyhow::{format_err, Result};
+use crate::{Error, Result};
 use log::*;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::mpsc;
 
 use webrtc::ice::agent::agent_config::AgentConfig;
@@ -9,59 +10,145 @@ use webrtc::ice::candidate::*;
 use webrtc::ice::network_type::*;
 use webrtc::ice::url::Url;
 
-pub async fn resolve_external_ip_maps() -> Result<Vec<String>> {
+/// Finds the public IP of this host with the STUN servers, as ext/host mappings for every local
+/// IPv4 address (the NAT 1:1 format of SettingEngine::set_nat_1to1_ips)
+/// Fails if no server reflexive candidate arrives within the timeout
+pub async fn resolve_external_ip_maps(
+    stun_servers: &[String],
+    timeout: Duration,
+) -> Result<Vec<String>> {
+    let urls = stun_servers
+        .iter()
+        .map(|url| Url::parse_url(url).map_err(|err| Error::Ice(err.into())))
+        .collect::<Result<Vec<_>>>()?;
+    if urls.is_empty() {
+        return Err(Error::Ice(webrtc::Error::new(
+            "no stun servers to resolve the external ip with".to_owned(),
+        )));
+    }
+
     let ice_agent = Arc::new(
         Agent::new(AgentConfig {
-            urls: vec![Url::parse_url("stun:stun.l.google.com:19302")?],
+            urls,
             network_types: vec![NetworkType::Udp4],
             ..Default::default()
         })
-        .await?,
+        .await
+        .map_err(|err| Error::Ice(err.into()))?,
     );
 
+    // None once gathering is complete
     let (tx, mut rx) = mpsc::channel(16);
 
     ice_agent.on_candidate(Box::new(
         move |c: Option<Arc<dyn Candidate + Send + Sync>>| {
             let tx_clone = tx.clone();
             Box::pin(async move {
-                if let Some(c) = c {
+                if let Some(c) = &c {
                     debug!(
                         "Gathered External Candidate: {:?} {:?}",
                         c.address(),
                         c.candidate_type()
                     );
-                    tx_clone
-                        .send((c.address(), c.candidate_type()))
-                        .await
-                        .unwrap();
                 }
+                let _ = tx_clone
+                    .send(c.map(|c| (c.address(), c.candidate_type())))
+                    .await;
             })
         },
     ));
 
-    ice_agent.gather_candidates()?;
+    ice_agent
+        .gather_candidates()
+        .map_err(|err| Error::Ice(err.into()))?;
+
+    let resolved = tokio::time::timeout(timeout, external_ip_maps(&mut rx)).await;
 
-    let mut hosts = vec![];
-    while let Some((addr, t)) = rx.recv().await {
+    if let Err(err) = ice_agent.close().await {
+        debug!("error closing ice agent: {}", err);
+    }
+
+    match resolved {
+        Ok(Some(maps)) => Ok(maps),
+        Ok(None) => Err(Error::Ice(webrtc::Error::new(
+            "could not resolve external ip, no stun server answered".to_owned(),
+        ))),
+        Err(_) => Err(Error::Ice(webrtc::Error::new(format!(
+            "could not resolve external ip within {:?}",
+            timeout
+        )))),
+    }
+}
+
+/// Maps the first server reflexive address to the host addresses gathered before it
+/// Without a host address yet the bare external IP is used, mapping every local address
+async fn external_ip_maps(
+    rx: &mut mpsc::Receiver<Option<(String, CandidateType)>>,
+) -> Option<Vec<String>> {
+    let mut hosts: Vec<String> = vec![];
+    while let Some(Some((addr, t))) = rx.recv().await {
         match t {
             CandidateType::Host => {
                 debug!("Resolved host ip {:?}", addr);
-                hosts.push(addr);
+                if !hosts.contains(&addr) {
+                    hosts.push(addr);
+                }
             }
             CandidateType::ServerReflexive => {
                 debug!("Resolved ext ip {:?}", addr);
-                let mut maps = vec![];
-
-                for h in hosts {
-                    maps.push(format!("{}/{}", addr, h));
+                if hosts.is_empty() {
+                    return Some(vec![addr]);
                 }
-
-                return Ok(maps);
+                return Some(
+                    hosts
+                        .iter()
+                        .map(|host| format!("{}/{}", addr, host))
+                        .collect(),
+                );
             }
             _ => {}
         }
     }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn maps(candidates: &[(&str, CandidateType)]) -> Option<Vec<String>> {
+        let (tx, mut rx) = mpsc::channel(16);
+        for (addr, t) in candidates {
+            tx.send(Some((addr.to_string(), *t))).await.unwrap();
+        }
+        tx.send(None).await.unwrap();
+        external_ip_maps(&mut rx).await
+    }
 
-    Err(format_err!("could not resolve external ip"))
+    #[tokio::test]
+    async fn maps_the_external_ip_to_hosts() {
+        let candidates = [
+            ("10.0.0.2", CandidateType::Host),
+            ("192.168.1.2", CandidateType::Host),
+            ("203.0.113.7", CandidateType::ServerReflexive),
+        ];
+        assert_eq!(
+            maps(&candidates).await.unwrap(),
+            vec!["203.0.113.7/10.0.0.2", "203.0.113.7/192.168.1.2"]
+        );
+    }
+
+    #[tokio::test]
+    async fn uses_the_bare_external_ip_before_any_host() {
+        let candidates = [
+            ("203.0.113.7", CandidateType::ServerReflexive),
+            ("10.0.0.2", CandidateType::Host),
+        ];
+        assert_eq!(maps(&candidates).await.unwrap(), vec!["203.0.113.7"]);
+    }
+
+    #[tokio::test]
+    async fn fails_without_a_server_reflexive_candidate() {
+        assert!(maps(&[("10.0.0.2", CandidateType::Host)]).await.is_none());
+    }
 }
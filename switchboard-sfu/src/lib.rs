@@ -1,5 +1,11 @@
+pub mod config;
+pub mod error;
 pub mod extip;
 pub mod sfu;
 pub mod signal;
+pub mod turn;
+
+pub use config::ServerConfig;
+pub use error::{Error, Result};
 
 //pub mod p2p;
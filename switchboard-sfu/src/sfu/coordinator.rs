@@ -1,43 +1,66 @@
 use super::session;
+use crate::{Error, Result};
 use async_mutex::Mutex;
 use async_trait::async_trait;
 use log::*;
 use std::collections::HashMap;
 use std::sync::Arc;
 
+/// Limits of a coordinator and the sessions it creates
+#[derive(Clone, Copy, Default, Debug)]
+pub struct CoordinatorConfig {
+    /// Sessions allowed at once, unlimited if None
+    pub max_sessions: Option<usize>,
+    pub session: session::SessionConfig,
+}
+
 #[async_trait]
 /// Coordinator is responsible for managing sessions
 pub trait Coordinator<S: session::Session> {
-    fn new() -> Arc<Self>;
-    async fn get_or_create_session(&self, id: session::Id) -> session::SessionHandle<S>;
+    fn new(cfg: CoordinatorConfig) -> Arc<Self>;
+    /// Returns the session, creating it unless that would exceed max_sessions
+    async fn get_or_create_session(&self, id: session::Id) -> Result<session::SessionHandle<S>>;
     async fn cleanup_session(&self, id: session::Id);
+    /// Number of sessions with peers (or not cleaned up yet)
+    async fn session_count(&self) -> usize;
 }
 
 /// LocalCoordinator is a simple coordinator impl that just holds sessions on a single node
 pub struct LocalCoordinator<S: session::Session> {
     pub sessions: Arc<Mutex<HashMap<session::Id, session::SessionHandle<S>>>>,
+    cfg: CoordinatorConfig,
 }
 
 #[async_trait]
 impl<S: session::Session + Send + Sync> Coordinator<S> for LocalCoordinator<S> {
-    fn new() -> Arc<LocalCoordinator<S>> {
+    fn new(cfg: CoordinatorConfig) -> Arc<LocalCoordinator<S>> {
         Arc::new(LocalCoordinator {
             sessions: Arc::new(Mutex::new(HashMap::new())),
+            cfg,
         })
     }
 
-    async fn get_or_create_session(&self, id: session::Id) -> session::SessionHandle<S> {
+    async fn get_or_create_session(&self, id: session::Id) -> Result<session::SessionHandle<S>> {
         let mut sessions = self.sessions.lock().await;
 
         if let Some(session) = sessions.get(&id) {
             info!("LocalCoordinator found existing session id={} ", id);
-            return session.clone();
+            return Ok(session.clone());
+        }
+
+        if self
+            .cfg
+            .max_sessions
+            .is_some_and(|max| sessions.len() >= max)
+        {
+            warn!("LocalCoordinator at max sessions, not starting id={}", id);
+            return Err(Error::CapacityExceeded("too many sessions".to_owned()));
         }
 
         info!("LocalCoodinator starting new session id={}", id);
-        let session = S::new(id.clone());
+        let session = S::new(id.clone(), self.cfg.session);
         sessions.insert(id.clone(), session.clone());
-        session
+        Ok(session)
     }
 
     async fn cleanup_session(&self, id: session::Id) {
@@ -50,4 +73,8 @@ impl<S: session::Session + Send + Sync> Coordinator<S> for LocalCoordinator<S> {
             }
         }
     }
+
+    async fn session_count(&self) -> usize {
+        self.sessions.lock().await.len()
+    }
 }
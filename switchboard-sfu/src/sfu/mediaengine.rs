@@ -11,9 +11,14 @@ const EXT_URI_SDES_RTP_SID: &str = "urn:ietf:params:rtp-hdrext:sdes:rtp-stream-i
 const EXT_URI_SDES_REP_SID: &str = "urn:ietf:params:rtp-hdrext:sdes:repaired-rtp-stream-id";
 const EXT_URI_AUDIO_LEVEL: &str = "urn:ietf:params:rtp-hdrext:ssrc-audio-level";
 
-pub fn register_default_codecs(media_engine: &mut MediaEngine) -> Result<()> {
+/// Codecs that can be enabled by name (the mime subtype, case insensitive)
+pub const CODEC_NAMES: &[&str] = &["opus", "g722", "pcmu", "pcma", "vp8", "vp9", "h264"];
+
+/// Registers the default codecs, limited to the enabled names (all of them if empty)
+/// Retransmission (rtx) codecs follow the codec they protect
+pub fn register_codecs(media_engine: &mut MediaEngine, enabled: &[String]) -> Result<()> {
     // Default Audio Codecs
-    for codec in vec![
+    for codec in [
         RTCRtpCodecParameters {
             capability: RTCRtpCodecCapability {
                 mime_type: MIME_TYPE_OPUS.to_owned(),
@@ -59,7 +64,9 @@ pub fn register_default_codecs(media_engine: &mut MediaEngine) -> Result<()> {
             ..Default::default()
         },
     ] {
-        media_engine.register_codec(codec, RTPCodecType::Audio)?;
+        if codec_enabled(enabled, &codec) {
+            media_engine.register_codec(codec, RTPCodecType::Audio)?;
+        }
     }
 
     let video_rtcp_feedback = vec![
@@ -80,7 +87,7 @@ pub fn register_default_codecs(media_engine: &mut MediaEngine) -> Result<()> {
             parameter: "pli".to_owned(),
         },
     ];
-    for codec in vec![
+    let video_codecs = [
         //RTCRtpCodecParameters {
         //    capability: RTCRtpCodecCapability {
         //        mime_type: MIME_TYPE_AV1.to_owned(),
@@ -313,15 +320,42 @@ pub fn register_default_codecs(media_engine: &mut MediaEngine) -> Result<()> {
             payload_type: 116,
             ..Default::default()
         },
-    ] {
-        media_engine.register_codec(codec, RTPCodecType::Video)?;
+    ];
+
+    let rtx_apts: Vec<String> = video_codecs
+        .iter()
+        .filter(|codec| codec_enabled(enabled, codec))
+        .map(|codec| format!("apt={}", codec.payload_type))
+        .collect();
+    for codec in video_codecs {
+        let register = match codec_name(&codec.capability.mime_type) {
+            "rtx" => rtx_apts.contains(&codec.capability.sdp_fmtp_line),
+            "ulpfec" => true,
+            _ => codec_enabled(enabled, &codec),
+        };
+        if register {
+            media_engine.register_codec(codec, RTPCodecType::Video)?;
+        }
     }
 
     Ok(())
 }
 
+/// Codec name of a mime type (or of a plain name)
+fn codec_name(mime_type: &str) -> &str {
+    mime_type.rsplit('/').next().unwrap_or(mime_type)
+}
+
+fn codec_enabled(enabled: &[String], codec: &RTCRtpCodecParameters) -> bool {
+    let name = codec_name(&codec.capability.mime_type);
+    enabled.is_empty()
+        || enabled
+            .iter()
+            .any(|enabled| codec_name(enabled).eq_ignore_ascii_case(name))
+}
+
 pub fn register_rtp_extension_simulcast(m: &mut MediaEngine) -> Result<()> {
-    for extension in vec![EXT_URI_SDES_MID, EXT_URI_SDES_RTP_SID, EXT_URI_SDES_REP_SID] {
+    for extension in [EXT_URI_SDES_MID, EXT_URI_SDES_RTP_SID, EXT_URI_SDES_REP_SID] {
         m.register_header_extension(
             RTCRtpHeaderExtensionCapability {
                 uri: extension.to_owned(),
@@ -335,7 +369,7 @@ pub fn register_rtp_extension_simulcast(m: &mut MediaEngine) -> Result<()> {
 }
 
 pub fn register_rtp_extension_audiolevel(m: &mut MediaEngine) -> Result<()> {
-    for extension in vec![
+    for extension in [
         EXT_URI_SDES_MID,
         EXT_URI_SDES_RTP_SID,
         EXT_URI_SDES_REP_SID,
@@ -350,4 +384,4 @@ pub fn register_rtp_extension_audiolevel(m: &mut MediaEngine) -> Result<()> {
         )?;
     }
     Ok(())
-}
\ No newline at end of file
+}
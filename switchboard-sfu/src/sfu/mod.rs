@@ -2,5 +2,7 @@ pub mod coordinator;
 pub mod peer;
 pub mod routing;
 pub mod session;
+pub mod subscription;
+pub mod tcp_mux;
 
-mod mediaengine;
+pub(crate) mod mediaengine;
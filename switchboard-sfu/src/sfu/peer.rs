@@ -1,24 +1,28 @@
-use anyhow::{format_err, Result};
 use async_mutex::Mutex;
 use enclose::enc;
 use futures::{SinkExt, StreamExt};
 use futures_channel::mpsc;
 use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::default::Default;
 use std::sync::Arc;
 use uuid::Uuid;
 use webrtc::api::setting_engine::SettingEngine;
 use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use webrtc::peer_connection::signaling_state::RTCSignalingState;
 use webrtc::rtp_transceiver::rtp_codec::{RTCRtpHeaderExtensionCapability, RTPCodecType};
 use webrtc::rtp_transceiver::RTCRtpTransceiver;
 
-use webrtc::api::interceptor_registry::register_default_interceptors;
+use webrtc::api::interceptor_registry::{configure_rtcp_reports, configure_twcc_receiver_only};
 use webrtc::api::media_engine::MediaEngine;
 use webrtc::api::APIBuilder;
 use webrtc::ice_transport::ice_candidate::{RTCIceCandidate, RTCIceCandidateInit};
 use webrtc::ice_transport::ice_server::RTCIceServer;
+use webrtc::interceptor::nack::generator::Generator;
 use webrtc::interceptor::registry::Registry;
 use webrtc::peer_connection::configuration::RTCConfiguration;
+use webrtc::peer_connection::offer_answer_options::RTCOfferOptions;
 use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
 use webrtc::peer_connection::RTCPeerConnection;
 use webrtc::rtcp;
@@ -29,21 +33,73 @@ use webrtc::track::track_remote::TrackRemote;
 use crate::sfu::mediaengine;
 use crate::sfu::routing::*;
 use crate::sfu::session::{self, SessionEvent};
+use crate::sfu::subscription::SubscriptionFilter;
+use crate::sfu::tcp_mux;
 use crate::signal::signal;
+use crate::{Error, Result};
 
 // Peer ID unique to the connection/websocket
 pub type Id = Uuid;
 
-const TRANSPORT_TARGET_PUB: u32 = 0;
-const TRANSPORT_TARGET_SUB: u32 = 1;
+/// Transport targets of trickle and ice_restart
+pub const TRANSPORT_TARGET_PUB: u32 = 0;
+pub const TRANSPORT_TARGET_SUB: u32 = 1;
 
 pub(super) type RtcpWriter = mpsc::Sender<Box<dyn rtcp::packet::Packet + Send + Sync>>;
 pub(super) type RtcpReader = mpsc::Receiver<Box<dyn rtcp::packet::Packet + Send + Sync>>;
 
+/// Role decides which peer connections a peer gets, and so whether it may publish and/or
+/// subscribe
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Publishes and subscribes
+    #[default]
+    Participant,
+    /// Subscribes only, has no publisher peer connection
+    Viewer,
+    /// Publishes only, has no subscriber peer connection
+    Ingest,
+}
+
+impl Role {
+    /// Returns the role with these capabilities, None if it can do neither
+    pub fn new(publish: bool, subscribe: bool) -> Option<Role> {
+        match (publish, subscribe) {
+            (true, true) => Some(Role::Participant),
+            (false, true) => Some(Role::Viewer),
+            (true, false) => Some(Role::Ingest),
+            (false, false) => None,
+        }
+    }
+
+    pub fn publishes(&self) -> bool {
+        matches!(self, Role::Participant | Role::Ingest)
+    }
+
+    pub fn subscribes(&self) -> bool {
+        matches!(self, Role::Participant | Role::Viewer)
+    }
+}
+
+#[derive(Clone)]
 pub struct PeerConfig {
     pub setting_engine: SettingEngine,
     pub rtc_config: RTCConfiguration,
     pub header_extensions: Vec<(RTCRtpHeaderExtensionCapability, RTPCodecType)>,
+    /// Codec names to negotiate (see mediaengine::CODEC_NAMES), every default codec if empty
+    pub codecs: Vec<String>,
+    pub router: MediaTrackRouterConfig,
+    /// Port of the ICE-TCP listener (see tcp_mux::TcpMux), a passive TCP candidate on it is
+    /// signaled with every UDP host candidate
+    pub ice_tcp_port: Option<u16>,
+    /// Route this peer's own published tracks back to its subscriber connection (echo tests)
+    pub loopback: bool,
+    /// Subscribe to every published track, otherwise tracks are picked with subscribe
+    pub auto_subscribe: bool,
+    /// App-defined identity shared with the other peers of the session
+    pub identity: Option<serde_json::Value>,
+    pub role: Role,
 }
 impl Default for PeerConfig {
     fn default() -> PeerConfig {
@@ -57,113 +113,425 @@ impl Default for PeerConfig {
                 ..Default::default()
             },
             header_extensions: vec![],
+            codecs: vec![],
+            router: MediaTrackRouterConfig::default(),
+            ice_tcp_port: None,
+            loopback: false,
+            auto_subscribe: true,
+            identity: None,
+            role: Role::default(),
         }
     }
 }
 
 /// Peer represents a WebRTC connection and it's tracks
-/// We hold two publisher, subscriber
+/// We hold up to two peer connections, publisher and subscriber, depending on the role
 pub struct Peer {
     pub id: Id,
-    pub publisher: Arc<RTCPeerConnection>,
-    pub pub_rtcp_writer: RtcpWriter,
+    pub identity: Option<serde_json::Value>,
+    pub loopback: bool,
+    role: Mutex<Role>,
 
-    pub subscriber: Arc<RTCPeerConnection>,
-    pub sub_rtcp_writer: RtcpWriter,
+    // None when the role doesn't publish
+    publisher: Mutex<Option<Arc<RTCPeerConnection>>>,
+    // None when the role doesn't subscribe
+    subscriber: Mutex<Option<Arc<RTCPeerConnection>>>,
 
     pub sub_pending_candidates: Arc<Mutex<Vec<RTCIceCandidateInit>>>,
 
-    pub signal_tx: signal::WriteStream,
+    // routers for tracks published by this peer (keyed by track id so simulcast layers group)
+    routers: Arc<Mutex<HashMap<String, MediaTrackRouterHandle>>>,
+    // labels for tracks this peer publishes, from its join/offer (keyed by track id)
+    track_labels: Arc<Mutex<HashMap<String, TrackLabels>>>,
+    // tracks this peer is subscribed to (keyed by track id)
+    subscribers: Arc<Mutex<HashMap<String, SubscribedTrack>>>,
+    // which published tracks this peer wants
+    subscriptions: Mutex<SubscriptionFilter>,
+    negotiation: Arc<Mutex<NegotiationBatch>>,
+
+    // peer connections are built again when the role changes
+    cfg: PeerConfig,
+    session_tx: session::WriteStream,
+    // swapped when the peer resumes on a new signal connection
+    pub signal_tx: Arc<signal::PeerSignal>,
 }
 
 impl Peer {
-    /// Creates a new Peer (with 2 peer connections)
+    /// Creates a new Peer with the peer connections its role needs
     pub async fn new(
         signal_tx: signal::WriteStream,
         session_tx: mpsc::Sender<SessionEvent>,
         cfg: PeerConfig,
     ) -> Result<Arc<Peer>> {
-        let (publisher, pub_rtcp_writer) = build_peer_connection(&cfg).await?;
-        let (subscriber, sub_rtcp_writer) = build_peer_connection(&cfg).await?;
-
-        let mut peer = Peer {
+        let peer = Peer {
             id: Uuid::new_v4(),
-            publisher,
-            pub_rtcp_writer,
-            subscriber,
-            sub_rtcp_writer,
+            identity: cfg.identity.clone(),
+            loopback: cfg.loopback,
+            role: Mutex::new(cfg.role),
+            publisher: Mutex::new(None),
+            subscriber: Mutex::new(None),
             sub_pending_candidates: Arc::new(Mutex::new(vec![])),
-            signal_tx: signal_tx.clone(),
+            routers: Arc::new(Mutex::new(HashMap::new())),
+            track_labels: Arc::new(Mutex::new(HashMap::new())),
+            subscribers: Arc::new(Mutex::new(HashMap::new())),
+            subscriptions: Mutex::new(if cfg.auto_subscribe {
+                SubscriptionFilter::all()
+            } else {
+                SubscriptionFilter::default()
+            }),
+            negotiation: Arc::new(Mutex::new(NegotiationBatch::default())),
+            session_tx,
+            signal_tx: Arc::new(signal::PeerSignal::new(signal_tx)),
+            cfg,
         };
 
-        peer.setup_signal_hooks(signal_tx, session_tx).await;
+        if let Err(err) = peer.connect(peer.cfg.role).await {
+            let _ = peer.close().await;
+            return Err(err);
+        }
 
         Ok(Arc::new(peer))
     }
 
+    pub async fn role(&self) -> Role {
+        *self.role.lock().await
+    }
+
+    /// Changes the role, building the peer connections it gains and closing the ones it loses
+    /// Closing the publisher unpublishes its tracks, closing the subscriber drops every
+    /// subscribed track. A new subscriber connection sends its own offer.
+    pub async fn set_role(&self, role: Role) -> Result<()> {
+        let mut current = self.role.lock().await;
+        if *current == role {
+            return Ok(());
+        }
+
+        if !role.publishes() {
+            if let Some(publisher) = self.publisher.lock().await.take() {
+                publisher.close().await.map_err(Error::PeerConnection)?;
+            }
+        }
+        if !role.subscribes() {
+            for (_, subscribed) in self.subscribers.lock().await.drain() {
+                subscribed.handle.stop();
+            }
+            self.sub_pending_candidates.lock().await.clear();
+            if let Some(subscriber) = self.subscriber.lock().await.take() {
+                subscriber.close().await.map_err(Error::PeerConnection)?;
+            }
+        }
+        self.connect(role).await?;
+
+        info!(
+            "peer id={} changed role {:?} -> {:?}",
+            self.id, *current, role
+        );
+        *current = role;
+        Ok(())
+    }
+
+    /// Builds the peer connections the role needs that don't exist yet
+    async fn connect(&self, role: Role) -> Result<()> {
+        if role.publishes() {
+            let mut publisher = self.publisher.lock().await;
+            if publisher.is_none() {
+                let (pc, rtcp_writer) = build_peer_connection(&self.cfg, TRANSPORT_TARGET_PUB)
+                    .await
+                    .map_err(Error::PeerConnection)?;
+                self.setup_publisher_hooks(&pc, rtcp_writer);
+                *publisher = Some(pc);
+            }
+        }
+
+        if role.subscribes() {
+            let mut subscriber = self.subscriber.lock().await;
+            if subscriber.is_none() {
+                // subscribed tracks send their own RTCP, the writer is unused
+                let (pc, _) = build_peer_connection(&self.cfg, TRANSPORT_TARGET_SUB)
+                    .await
+                    .map_err(Error::PeerConnection)?;
+                self.setup_subscriber_hooks(&pc).await;
+                *subscriber = Some(pc);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn publisher(&self) -> Result<Arc<RTCPeerConnection>> {
+        self.publisher
+            .lock()
+            .await
+            .clone()
+            .ok_or_else(|| Error::Forbidden("peer role may not publish".to_owned()))
+    }
+
+    async fn subscriber(&self) -> Result<Arc<RTCPeerConnection>> {
+        self.subscriber
+            .lock()
+            .await
+            .clone()
+            .ok_or_else(|| Error::Forbidden("peer role may not subscribe".to_owned()))
+    }
+
+    /// Answers an offer for the publisher peer connection, fails with Error::Forbidden if the
+    /// role doesn't publish
     pub async fn publisher_get_answer_for_offer(
         &self,
         offer: RTCSessionDescription,
     ) -> Result<RTCSessionDescription> {
+        let publisher = self.publisher().await?;
+
+        // webrtc restarts ICE when a renegotiation offer changes the credentials
+        if let Some(current) = publisher.remote_description().await {
+            if ice_ufrag(&current.sdp) != ice_ufrag(&offer.sdp) {
+                info!("peer id={} publisher ice restart", self.id);
+            }
+        }
+
         debug!("publisher set remote description");
-        self.publisher.set_remote_description(offer).await?;
+        publisher
+            .set_remote_description(offer)
+            .await
+            .map_err(Error::Negotiation)?;
 
-        let answer = self.publisher.create_answer(None).await?;
-        self.publisher.set_local_description(answer).await?;
+        let answer = publisher
+            .create_answer(None)
+            .await
+            .map_err(Error::Negotiation)?;
+        publisher
+            .set_local_description(answer)
+            .await
+            .map_err(Error::Negotiation)?;
+
+        let answer = publisher.local_description().await.ok_or_else(|| {
+            Error::Negotiation(webrtc::Error::new(
+                "couldn't set local description".to_owned(),
+            ))
+        })?;
+        Ok(with_tcp_candidates(answer, self.cfg.ice_tcp_port))
+    }
 
-        match self.publisher.local_description().await {
-            Some(answer) => Ok(answer),
-            None => Err(format_err!("couldn't set local description")),
-        }
+    /// Stores labels for tracks about to be published, call before negotiating the offer
+    /// that adds them
+    pub async fn set_track_labels(&self, tracks: HashMap<String, TrackLabels>) {
+        self.track_labels.lock().await.extend(tracks);
     }
 
-    pub async fn subscriber_create_offer(&self) -> Result<RTCSessionDescription> {
-        let offer = self.subscriber.create_offer(None).await?;
+    pub async fn subscriber_create_offer(
+        &self,
+        ice_restart: bool,
+    ) -> Result<RTCSessionDescription> {
+        let subscriber = self.subscriber().await?;
+        let offer = subscriber
+            .create_offer(Some(RTCOfferOptions {
+                ice_restart,
+                ..Default::default()
+            }))
+            .await
+            .map_err(Error::Negotiation)?;
 
-        let mut offer_gathering_complete = self.subscriber.gathering_complete_promise().await;
-        self.subscriber.set_local_description(offer).await?;
+        let mut offer_gathering_complete = subscriber.gathering_complete_promise().await;
+        subscriber
+            .set_local_description(offer)
+            .await
+            .map_err(Error::Negotiation)?;
         let _ = offer_gathering_complete.recv().await;
 
-        let offer = self.subscriber.local_description().await.unwrap();
-        Ok(offer)
+        let offer = subscriber.local_description().await.ok_or_else(|| {
+            Error::Negotiation(webrtc::Error::new(
+                "couldn't set local description".to_owned(),
+            ))
+        })?;
+        Ok(with_tcp_candidates(offer, self.cfg.ice_tcp_port))
     }
 
     pub async fn subscriber_set_answer(&self, answer: RTCSessionDescription) -> Result<()> {
-        self.subscriber.set_remote_description(answer).await?;
+        let subscriber = self.subscriber().await?;
+        subscriber
+            .set_remote_description(answer)
+            .await
+            .map_err(Error::Negotiation)?;
 
-        let mut pending_candidates = self.sub_pending_candidates.lock().await;
-        while let Some(candidate) = (*pending_candidates).pop() {
-            if let Err(err) = self.subscriber.add_ice_candidate(candidate).await {
-                error!("error adding ice candidate: {}", err);
+        {
+            let mut pending_candidates = self.sub_pending_candidates.lock().await;
+            while let Some(candidate) = (*pending_candidates).pop() {
+                if let Err(err) = subscriber.add_ice_candidate(candidate).await {
+                    error!("error adding ice candidate: {}", err);
+                }
             }
         }
 
+        let restart = {
+            let mut negotiation = self.negotiation.lock().await;
+            negotiation.ice_restarting = false;
+            std::mem::take(&mut negotiation.ice_restart)
+        };
+        if restart {
+            subscriber_restart_ice(
+                &subscriber,
+                &self.signal_tx,
+                self.cfg.ice_tcp_port,
+                &self.negotiation,
+            )
+            .await;
+        }
+
+        Ok(())
+    }
+
+    /// Restarts subscriber ICE, for clients whose network changed
+    /// The subscriber gets an offer with new ICE credentials (once a pending offer is answered).
+    /// There's no server side publisher restart, the client sends an offer with new credentials.
+    pub async fn restart_subscriber_ice(&self) -> Result<()> {
+        let subscriber = self.subscriber().await?;
+        info!("peer id={} restarting subscriber ice", self.id);
+        subscriber_restart_ice(
+            &subscriber,
+            &self.signal_tx,
+            self.cfg.ice_tcp_port,
+            &self.negotiation,
+        )
+        .await;
         Ok(())
     }
 
     // Adds a MediaTrackSubscriber to this peer's subscriber peer_connection
-    pub async fn add_media_track_subscriber(&self, mut subscriber: MediaTrackSubscriber) {
-        let rtp_sender: Arc<RTCRtpSender> = subscriber
-            .add_to_peer_connection(&self.subscriber)
-            .await
-            .expect("error adding track subscriber to peer_connection");
+    pub async fn add_media_track_subscriber(
+        &self,
+        mut subscriber: MediaTrackSubscriber,
+    ) -> Result<()> {
+        let sub_pc = self.subscriber().await?;
+        let rtp_sender: Arc<RTCRtpSender> = subscriber.add_to_peer_connection(&sub_pc).await?;
+
+        let track_id = subscriber.id();
+        self.subscribers.lock().await.insert(
+            track_id.clone(),
+            SubscribedTrack {
+                handle: subscriber.handle(),
+                rtp_sender: rtp_sender.clone(),
+            },
+        );
 
-        let sub_pc = Arc::downgrade(&self.subscriber);
+        let sub_pc = Arc::downgrade(&sub_pc);
+        let subscribers = self.subscribers.clone();
         tokio::spawn(async move {
             subscriber.rtp_event_loop().await;
 
+            // The track may already be removed by remove_media_track_subscriber (and maybe
+            // subscribed to again)
+            {
+                let mut subscribers = subscribers.lock().await;
+                match subscribers.get(&track_id) {
+                    Some(subscribed) if Arc::ptr_eq(&subscribed.rtp_sender, &rtp_sender) => {
+                        subscribers.remove(&track_id);
+                    }
+                    _ => return,
+                }
+            }
+
             if let Some(sub_pc) = sub_pc.upgrade() {
                 if sub_pc.connection_state() == RTCPeerConnectionState::Closed {
                     return;
                 }
-                sub_pc
-                    .remove_track(&rtp_sender)
-                    .await
-                    .expect("error removing track from subscriber");
-
-                debug!("Removed track from subscriber");
+                match sub_pc.remove_track(&rtp_sender).await {
+                    Ok(_) => debug!("Removed track from subscriber"),
+                    Err(err) => error!("error removing track from subscriber: {}", err),
+                }
             }
         });
+
+        Ok(())
+    }
+
+    /// Stops forwarding a subscribed track and removes it from the subscriber peer_connection
+    pub async fn remove_media_track_subscriber(&self, track_id: &str) -> Result<()> {
+        let subscribed = match self.subscribers.lock().await.remove(track_id) {
+            Some(subscribed) => subscribed,
+            None => {
+                return Err(Error::Routing(format!(
+                    "not subscribed to track id={}",
+                    track_id
+                )))
+            }
+        };
+
+        subscribed.handle.stop();
+        let subscriber = self.subscriber().await?;
+        if subscriber.connection_state() != RTCPeerConnectionState::Closed {
+            subscriber
+                .remove_track(&subscribed.rtp_sender)
+                .await
+                .map_err(Error::PeerConnection)?;
+        }
+
+        debug!("Removed track id={} from subscriber", track_id);
+        Ok(())
+    }
+
+    /// Returns true if this peer currently receives the track
+    pub async fn is_subscribed(&self, track_id: &str) -> bool {
+        self.subscribers.lock().await.contains_key(track_id)
+    }
+
+    /// Ids of every track this peer currently receives
+    pub async fn subscribed_track_ids(&self) -> Vec<String> {
+        self.subscribers.lock().await.keys().cloned().collect()
+    }
+
+    /// Returns true if the subscription filter matches the track
+    pub async fn wants_track(&self, track_id: &str, kind: &str) -> bool {
+        self.subscriptions.lock().await.wants(track_id, kind)
+    }
+
+    /// Adds tracks (by id) and kinds to the subscription filter
+    pub async fn subscribe(&self, track_ids: &[String], kinds: &[String]) {
+        self.subscriptions.lock().await.subscribe(track_ids, kinds);
+    }
+
+    /// Removes tracks (by id) and kinds from the subscription filter
+    pub async fn unsubscribe(&self, track_ids: &[String], kinds: &[String]) {
+        self.subscriptions
+            .lock()
+            .await
+            .unsubscribe(track_ids, kinds);
+    }
+
+    /// Selects the simulcast layer (rid) forwarded to this peer for a subscribed track
+    /// None returns the track to automatic layer selection
+    pub async fn select_layer(&self, track_id: &str, rid: Option<String>) -> Result<()> {
+        match self.subscribers.lock().await.get(track_id) {
+            Some(subscribed) => {
+                subscribed.handle.select_layer(rid);
+                Ok(())
+            }
+            None => Err(Error::Routing(format!(
+                "not subscribed to track id={}",
+                track_id
+            ))),
+        }
+    }
+
+    /// Holds back subscriber renegotiation until the matching end_negotiation_batch, so adding
+    /// or removing several tracks results in a single offer
+    pub async fn begin_negotiation_batch(&self) {
+        self.negotiation.lock().await.depth += 1;
+    }
+
+    /// Sends one subscriber offer if negotiation was needed during the batch
+    pub async fn end_negotiation_batch(&self) {
+        let pending = {
+            let mut negotiation = self.negotiation.lock().await;
+            negotiation.depth = negotiation.depth.saturating_sub(1);
+            negotiation.depth == 0 && std::mem::take(&mut negotiation.pending)
+        };
+
+        if pending {
+            if let Some(subscriber) = self.subscriber.lock().await.clone() {
+                subscriber_negotiate(&subscriber, &self.signal_tx, self.cfg.ice_tcp_port, None)
+                    .await;
+            }
+        }
     }
 
     pub async fn trickle_ice_candidate(
@@ -172,136 +540,325 @@ impl Peer {
         candidate: RTCIceCandidateInit,
     ) -> Result<()> {
         match target {
-            TRANSPORT_TARGET_PUB => {
-                if let Err(err) = self.publisher.add_ice_candidate(candidate).await {
-                    error!("error adding ice candidate: {}", err);
-                }
-            }
-            TRANSPORT_TARGET_SUB => match self.subscriber.remote_description().await {
-                None => {
-                    let mut pending_candidates = self.sub_pending_candidates.lock().await;
-                    debug!("subscriber pending candidate added");
-                    pending_candidates.push(candidate);
-                }
-                Some(_) => {
-                    if let Err(err) = self.subscriber.add_ice_candidate(candidate).await {
-                        error!("error adding ice candidate: {}", err);
+            TRANSPORT_TARGET_PUB => self
+                .publisher()
+                .await?
+                .add_ice_candidate(candidate)
+                .await
+                .map_err(Error::Ice),
+            TRANSPORT_TARGET_SUB => {
+                let subscriber = self.subscriber().await?;
+                match subscriber.remote_description().await {
+                    None => {
+                        let mut pending_candidates = self.sub_pending_candidates.lock().await;
+                        debug!("subscriber pending candidate added");
+                        pending_candidates.push(candidate);
+                        Ok(())
                     }
+                    Some(_) => subscriber
+                        .add_ice_candidate(candidate)
+                        .await
+                        .map_err(Error::Ice),
                 }
-            },
+            }
 
-            _ => {}
+            _ => Err(Error::Signaling(format!(
+                "unknown trickle target {}",
+                target
+            ))),
         }
-        Ok(())
     }
 
-    /// Closes both the publisher and subscriber peer connection
-    pub async fn close(&self) {
-        self.publisher
-            .close()
-            .await
-            .expect("error closing publisher");
-        self.subscriber
-            .close()
-            .await
-            .expect("error closing subscriber");
+    /// Stops forwarding the subscribed tracks and closes the publisher and subscriber peer
+    /// connections
+    pub async fn close(&self) -> Result<()> {
+        for (_, subscribed) in self.subscribers.lock().await.drain() {
+            subscribed.handle.stop();
+        }
+        let publisher = match self.publisher.lock().await.take() {
+            Some(publisher) => publisher.close().await,
+            None => Ok(()),
+        };
+        let subscriber = match self.subscriber.lock().await.take() {
+            Some(subscriber) => subscriber.close().await,
+            None => Ok(()),
+        };
+
+        publisher.and(subscriber).map_err(Error::PeerConnection)
     }
 
-    /// This installs callbacks onto the peer connections that will send  
+    /// This installs callbacks onto the publisher peer connection that will send
     /// signal::Event's back to the controlling websocket
     /// session::SessionEvent's to the controlling session
-    pub async fn setup_signal_hooks(
-        &mut self,
-        sig_tx: signal::WriteStream,
-        session_tx: session::WriteStream,
-    ) {
-        self.publisher
-            .on_ice_candidate(Box::new(enc!( (sig_tx) move |c: Option<RTCIceCandidate>| {
-                Box::pin(enc!( (sig_tx) async move {
-                    if let Some(c) = c {
-                        info!("on ice candidate publisher: {}", c);
-                        sig_tx.unbounded_send(Ok(signal::Event::TrickleIce(signal::TrickleNotification {
-                            target: TRANSPORT_TARGET_PUB,
-                            candidate: c
-                                .to_json()
-                                .expect("error converting to json")
-                                .into(),
-                        }))).expect("error sending ice");
-                    }
-                }))
-            })));
-
-        let pub_rtcp_tx = self.pub_rtcp_writer.clone();
-        self.publisher
+    fn setup_publisher_hooks(&self, publisher: &RTCPeerConnection, pub_rtcp_tx: RtcpWriter) {
+        let sig_tx = self.signal_tx.clone();
+        let ice_tcp_port = self.cfg.ice_tcp_port;
+        publisher.on_ice_candidate(Box::new(enc!( (sig_tx) move |c: Option<RTCIceCandidate>| {
+            Box::pin(enc!( (sig_tx) async move {
+                if let Some(c) = c {
+                    info!("on ice candidate publisher: {}", c);
+                    send_ice_candidate(&sig_tx, TRANSPORT_TARGET_PUB, c, ice_tcp_port);
+                }
+            }))
+        })));
+
+        let session_tx = self.session_tx.clone();
+        let router_config = self.cfg.router.clone();
+        let routers = self.routers.clone();
+        let track_labels = self.track_labels.clone();
+        let peer_id = self.id;
+        publisher
             .on_track(Box::new(enc!( (session_tx) {
-                move |track: Arc<TrackRemote>, receiver: Arc<RTCRtpReceiver>, _: Arc<RTCRtpTransceiver>| {
-                    Box::pin( enc!( (mut session_tx, pub_rtcp_tx) async move {
+                move |track: Arc<TrackRemote>, receiver: Arc<RTCRtpReceiver>, transceiver: Arc<RTCRtpTransceiver>| {
+                    Box::pin( enc!( (mut session_tx, pub_rtcp_tx, routers, track_labels, router_config) async move {
 
                         tokio::spawn(async move {
-                            let id = track.id();
-                            let (media_track_router, closed) = MediaTrackRouter::new(track, receiver, pub_rtcp_tx).await;
-                            session_tx.send(SessionEvent::TrackPublished(media_track_router.clone())).await.expect("error sending track router to session");
+                            // Router ids are derived from the transceiver mid so tracks of
+                            // different peers never collide, even with identical track ids
+                            let mid = transceiver.mid().map(|mid| mid.to_string()).unwrap_or_else(|| track.id());
+                            let id = format!("{}:{}", peer_id, mid);
+
+                            // Simulcast fires on_track once per rid, group them into one router
+                            let mut publishing = routers.lock().await;
+                            if !track.rid().is_empty() {
+                                if let Some(router) = publishing.get(&id) {
+                                    router.lock().await.add_layer(track).await;
+                                    drop(publishing);
+                                    if session_tx.send(SessionEvent::TrackUpdated(id)).await.is_err() {
+                                        error!("session closed, couldn't update track");
+                                    }
+                                    return;
+                                }
+                            }
+
+                            let labels = track_labels.lock().await.get(&track.id()).cloned().unwrap_or_default();
+                            let (media_track_router, closed) = MediaTrackRouter::new(id.clone(), peer_id, labels, track, receiver, pub_rtcp_tx, router_config).await;
+                            publishing.insert(id.clone(), media_track_router.clone());
+                            drop(publishing);
+
+                            if session_tx.send(SessionEvent::TrackPublished(media_track_router.clone())).await.is_err() {
+                                error!("session closed, couldn't publish track");
+                                return;
+                            }
                             let _ = closed.await;
-                            session_tx.send(SessionEvent::TrackRemoved(id)).await.expect("error sending track removed");
+
+                            // A new track may have replaced this one on the same transceiver
+                            {
+                                let mut publishing = routers.lock().await;
+                                if publishing.get(&id).is_some_and(|router| Arc::ptr_eq(router, &media_track_router)) {
+                                    publishing.remove(&id);
+                                }
+                            }
+                            if session_tx.send(SessionEvent::TrackRemoved(media_track_router)).await.is_err() {
+                                debug!("session closed before track was removed");
+                            }
                         });
                 }))
                 }}
             )));
+    }
 
-        let _ = self
-            .subscriber
-            .create_data_channel("switchboard-rx", None)
-            .await;
-
-        self.subscriber
-            .on_ice_candidate(Box::new(enc!( (sig_tx) move |c: Option<RTCIceCandidate>| {
-                Box::pin(enc!( (sig_tx) async move {
-                    if let Some(c) = c {
-                        info!("on ice candidate subscriber: {}", c);
-                        sig_tx.unbounded_send(Ok(signal::Event::TrickleIce(signal::TrickleNotification {
-                            target: TRANSPORT_TARGET_SUB,
-                            candidate: c
-                                .to_json()
-                                .expect("error converting to json")
-                                .into(),
-                        }))).expect("error sending ice");
+    /// This installs callbacks onto the subscriber peer connection that will send
+    /// ice candidates and offers back to the controlling websocket
+    async fn setup_subscriber_hooks(&self, subscriber: &Arc<RTCPeerConnection>) {
+        let _ = subscriber.create_data_channel("switchboard-rx", None).await;
+
+        let sig_tx = self.signal_tx.clone();
+        let ice_tcp_port = self.cfg.ice_tcp_port;
+        subscriber.on_ice_candidate(Box::new(enc!( (sig_tx) move |c: Option<RTCIceCandidate>| {
+            Box::pin(enc!( (sig_tx) async move {
+                if let Some(c) = c {
+                    info!("on ice candidate subscriber: {}", c);
+                    send_ice_candidate(&sig_tx, TRANSPORT_TARGET_SUB, c, ice_tcp_port);
+                }
+            }))
+        })));
+
+        let sub_pc = Arc::downgrade(subscriber);
+        let negotiation = self.negotiation.clone();
+        let peer_id = self.id;
+        subscriber.on_peer_connection_state_change(Box::new(enc!( (sig_tx, sub_pc, negotiation) move |state: RTCPeerConnectionState| {
+            Box::pin(enc!( (sig_tx, sub_pc, negotiation) async move {
+                if !matches!(state, RTCPeerConnectionState::Disconnected | RTCPeerConnectionState::Failed) {
+                    return;
+                }
+                info!("peer id={} subscriber connection {}, restarting ice", peer_id, state);
+
+                // the ICE agent can't restart from its own state change callback
+                tokio::spawn(async move {
+                    // disconnected is usually followed by failed, one restart covers both
+                    {
+                        let negotiation = negotiation.lock().await;
+                        if negotiation.ice_restart || negotiation.ice_restarting {
+                            debug!("peer id={} subscriber ice restart already pending", peer_id);
+                            return;
+                        }
                     }
-                }))
-            })));
+                    if let Some(sub_pc) = sub_pc.upgrade() {
+                        subscriber_restart_ice(&sub_pc, &sig_tx, ice_tcp_port, &negotiation).await;
+                    }
+                });
+            }))
+        })));
+
+        subscriber.on_negotiation_needed(Box::new(enc!( (sig_tx, sub_pc, negotiation) move || {
+            Box::pin(enc!( (sig_tx, sub_pc, negotiation) async move {
+                info!("subscriber on_negotiation_needed");
+
+                {
+                    let mut negotiation = negotiation.lock().await;
+                    if negotiation.depth > 0 {
+                        negotiation.pending = true;
+                        return;
+                    }
+                }
 
-        let sub_pc = Arc::downgrade(&self.subscriber);
-        self.subscriber
-            .on_negotiation_needed(Box::new(enc!( (sig_tx, sub_pc) move || {
-                Box::pin(enc!( (sig_tx, sub_pc) async move {
-                    info!("subscriber on_negotiation_needed");
+                if let Some(sub_pc) = sub_pc.upgrade() {
+                    subscriber_negotiate(&sub_pc, &sig_tx, ice_tcp_port, None).await;
+                }
+            }))
+        })));
+    }
+}
 
-                    if let Some(sub_pc) = sub_pc.upgrade() {
-                        if sub_pc.connection_state() == RTCPeerConnectionState::Closed {
-                                return;
-                        }
+struct SubscribedTrack {
+    handle: MediaTrackSubscriberHandle,
+    rtp_sender: Arc<RTCRtpSender>,
+}
 
-                        let offer = sub_pc.create_offer(None).await.expect("could not create subscriber offer:");
-                        sub_pc.set_local_description(offer).await.expect("could not set local description");
-                        let offer = sub_pc.local_description().await.unwrap();
+#[derive(Default)]
+struct NegotiationBatch {
+    depth: usize,
+    // negotiation was needed while a batch was open
+    pending: bool,
+    // an ICE restart is due once the pending offer is answered
+    ice_restart: bool,
+    // an ICE restart offer was sent and not answered yet
+    ice_restarting: bool,
+}
 
-                        info!("subscriber sending offer");
-                        if let Err(_) = sig_tx.unbounded_send(Ok(signal::Event::SubscriberOffer(offer))) {
-                            error!("signal connection closed");
-                        }
-                    }
-                }))
-            })));
+/// Sends a gathered ICE candidate to the signal connection, followed by its ICE-TCP twin
+fn send_ice_candidate(
+    sig_tx: &signal::PeerSignal,
+    target: u32,
+    c: RTCIceCandidate,
+    ice_tcp_port: Option<u16>,
+) {
+    let candidate = match c.to_json() {
+        Ok(candidate) => candidate,
+        Err(err) => {
+            error!("error converting ice candidate to json: {}", err);
+            return;
+        }
+    };
+
+    let tcp_candidate = ice_tcp_port
+        .and_then(|port| tcp_mux::tcp_candidate(&candidate.candidate, port))
+        .map(|tcp| RTCIceCandidateInit {
+            candidate: tcp,
+            ..candidate.clone()
+        });
+    for candidate in std::iter::once(candidate).chain(tcp_candidate) {
+        let trickle = signal::TrickleNotification {
+            target,
+            candidate: candidate.into(),
+        };
+        sig_tx.send(signal::Event::TrickleIce(trickle));
+    }
+}
+
+/// Adds the ICE-TCP candidates to a session description sent to the client
+fn with_tcp_candidates(
+    mut desc: RTCSessionDescription,
+    ice_tcp_port: Option<u16>,
+) -> RTCSessionDescription {
+    if let Some(port) = ice_tcp_port {
+        desc.sdp = tcp_mux::with_tcp_candidates(&desc.sdp, port);
+    }
+    desc
+}
+
+/// Creates a new subscriber offer and sends it to the signal connection, returns whether it was sent
+async fn subscriber_negotiate(
+    sub_pc: &RTCPeerConnection,
+    sig_tx: &signal::PeerSignal,
+    ice_tcp_port: Option<u16>,
+    options: Option<RTCOfferOptions>,
+) -> bool {
+    if sub_pc.connection_state() == RTCPeerConnectionState::Closed {
+        return false;
+    }
+
+    let offer = match sub_pc.create_offer(options).await {
+        Ok(offer) => offer,
+        Err(err) => {
+            error!("could not create subscriber offer: {}", err);
+            return false;
+        }
+    };
+    if let Err(err) = sub_pc.set_local_description(offer).await {
+        error!("could not set subscriber local description: {}", err);
+        return false;
     }
+    let offer = match sub_pc.local_description().await {
+        Some(offer) => offer,
+        None => return false,
+    };
+
+    info!("subscriber sending offer");
+    sig_tx.send(signal::Event::SubscriberOffer(with_tcp_candidates(
+        offer,
+        ice_tcp_port,
+    )));
+    true
+}
+
+/// Sends a subscriber offer with new ICE credentials
+/// With an offer already pending the restart waits for its answer, so the client never gets
+/// an offer it can't answer
+async fn subscriber_restart_ice(
+    sub_pc: &RTCPeerConnection,
+    sig_tx: &signal::PeerSignal,
+    ice_tcp_port: Option<u16>,
+    negotiation: &Mutex<NegotiationBatch>,
+) {
+    {
+        let mut negotiation = negotiation.lock().await;
+        if sub_pc.signaling_state() != RTCSignalingState::Stable {
+            debug!("subscriber offer pending, ice restart deferred");
+            negotiation.ice_restart = true;
+            return;
+        }
+        negotiation.ice_restarting = true;
+    }
+
+    let options = RTCOfferOptions {
+        ice_restart: true,
+        ..Default::default()
+    };
+    if !subscriber_negotiate(sub_pc, sig_tx, ice_tcp_port, Some(options)).await {
+        negotiation.lock().await.ice_restarting = false;
+    }
+}
+
+/// ICE username fragment of a session description
+fn ice_ufrag(sdp: &str) -> Option<&str> {
+    sdp.lines()
+        .find_map(|line| line.strip_prefix("a=ice-ufrag:"))
+        .map(str::trim)
 }
 
 /// Helper to build peer connections with the appropriate configuration
-async fn build_peer_connection(cfg: &PeerConfig) -> Result<(Arc<RTCPeerConnection>, RtcpWriter)> {
+async fn build_peer_connection(
+    cfg: &PeerConfig,
+    target: u32,
+) -> webrtc::error::Result<(Arc<RTCPeerConnection>, RtcpWriter)> {
     // Create a MediaEngine object to configure the supported codec
     let mut m = MediaEngine::default();
-    mediaengine::register_default_codecs(&mut m)?;
+    mediaengine::register_codecs(&mut m, &cfg.codecs)?;
 
     for (capability, codec_type) in &cfg.header_extensions {
-        m.register_header_extension(capability.clone(), codec_type.clone(), None)?;
+        m.register_header_extension(capability.clone(), *codec_type, None)?;
     }
 
     #[cfg(feature = "simulcast")]
@@ -316,8 +873,13 @@ async fn build_peer_connection(cfg: &PeerConfig) -> Result<(Arc<RTCPeerConnectio
     // for each PeerConnection.
     let mut registry = Registry::new();
 
-    // Use the default set of Interceptors
-    registry = register_default_interceptors(registry, &mut m)?;
+    // Default set of Interceptors, minus the NACK responder: subscriber NACKs are answered
+    // from the MediaTrackRouter packet cache, the publisher only needs to generate NACKs
+    if target == TRANSPORT_TARGET_PUB {
+        registry.add(Box::new(Generator::builder()));
+    }
+    registry = configure_rtcp_reports(registry);
+    registry = configure_twcc_receiver_only(registry, &mut m)?;
 
     // Create the API object with the MediaEngine
     let api = APIBuilder::new()
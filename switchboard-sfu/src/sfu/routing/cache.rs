@@ -0,0 +1,94 @@
+use async_mutex::Mutex;
+use std::sync::Arc;
+use webrtc::rtp;
+
+// Power of two so the ring stays aligned when sequence numbers wrap
+const PACKET_CACHE_SIZE: usize = 1024;
+
+pub(super) type PacketCacheHandle = Arc<Mutex<PacketCache>>;
+
+/// PacketCache keeps the most recent RTP packets of a single source (ssrc)
+/// so a MediaTrackRouter can answer subscriber NACKs without asking the publisher
+pub(super) struct PacketCache {
+    packets: Vec<Option<rtp::packet::Packet>>,
+}
+
+impl PacketCache {
+    pub fn new() -> PacketCache {
+        PacketCache {
+            packets: vec![None; PACKET_CACHE_SIZE],
+        }
+    }
+
+    pub fn push(&mut self, packet: &rtp::packet::Packet) {
+        let idx = packet.header.sequence_number as usize % PACKET_CACHE_SIZE;
+        self.packets[idx] = Some(packet.clone());
+    }
+
+    pub fn get(&self, sequence_number: u16) -> Option<rtp::packet::Packet> {
+        match &self.packets[sequence_number as usize % PACKET_CACHE_SIZE] {
+            Some(p) if p.header.sequence_number == sequence_number => Some(p.clone()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(seq: u16) -> rtp::packet::Packet {
+        rtp::packet::Packet {
+            header: rtp::header::Header {
+                sequence_number: seq,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn cached(cache: &PacketCache, seq: u16) -> Option<u16> {
+        cache.get(seq).map(|p| p.header.sequence_number)
+    }
+
+    #[test]
+    fn stores_and_looks_up() {
+        let mut cache = PacketCache::new();
+        for seq in 100..110 {
+            cache.push(&packet(seq));
+        }
+
+        assert_eq!(cached(&cache, 100), Some(100));
+        assert_eq!(cached(&cache, 109), Some(109));
+        assert_eq!(cached(&cache, 110), None);
+        assert_eq!(cached(&cache, 99), None);
+    }
+
+    #[test]
+    fn misses_overwritten_slots() {
+        let mut cache = PacketCache::new();
+        cache.push(&packet(5));
+        cache.push(&packet(5 + PACKET_CACHE_SIZE as u16));
+
+        // same slot, the old packet is gone
+        assert_eq!(cached(&cache, 5), None);
+        assert_eq!(
+            cached(&cache, 5 + PACKET_CACHE_SIZE as u16),
+            Some(5 + PACKET_CACHE_SIZE as u16)
+        );
+    }
+
+    #[test]
+    fn handles_wraparound() {
+        let mut cache = PacketCache::new();
+        for seq in (u16::MAX - 4..=u16::MAX).chain(0..5) {
+            cache.push(&packet(seq));
+        }
+
+        for seq in (u16::MAX - 4..=u16::MAX).chain(0..5) {
+            assert_eq!(cached(&cache, seq), Some(seq));
+        }
+        assert_eq!(cached(&cache, u16::MAX - 5), None);
+        assert_eq!(cached(&cache, 5), None);
+    }
+}
@@ -0,0 +1,267 @@
+use webrtc::api::media_engine::{MIME_TYPE_AV1, MIME_TYPE_H264, MIME_TYPE_VP8, MIME_TYPE_VP9};
+
+/// Returns true if the RTP payload starts a keyframe for the given codec mime type
+/// Returns None for codecs we can't inspect (including audio)
+pub(super) fn is_keyframe(mime_type: &str, payload: &[u8]) -> Option<bool> {
+    if mime_type.eq_ignore_ascii_case(MIME_TYPE_VP8) {
+        Some(is_vp8_keyframe(payload))
+    } else if mime_type.eq_ignore_ascii_case(MIME_TYPE_VP9) {
+        Some(is_vp9_keyframe(payload))
+    } else if mime_type.eq_ignore_ascii_case(MIME_TYPE_H264) {
+        Some(is_h264_keyframe(payload))
+    } else if mime_type.eq_ignore_ascii_case(MIME_TYPE_AV1) {
+        Some(is_av1_keyframe(payload))
+    } else {
+        None
+    }
+}
+
+/// VP8 payload descriptor (RFC 7741 section 4.2) followed by the VP8 payload header
+fn is_vp8_keyframe(payload: &[u8]) -> bool {
+    if payload.is_empty() {
+        return false;
+    }
+
+    let start_of_partition = payload[0] & 0x10 != 0;
+    let partition_id = payload[0] & 0x07;
+    if !start_of_partition || partition_id != 0 {
+        return false;
+    }
+
+    let mut offset = 1;
+    if payload[0] & 0x80 != 0 {
+        // X: extended control bits present
+        if payload.len() <= offset {
+            return false;
+        }
+        let ext = payload[offset];
+        offset += 1;
+
+        if ext & 0x80 != 0 {
+            // I: PictureID, 7 or 15 bits depending on M
+            if payload.len() <= offset {
+                return false;
+            }
+            offset += if payload[offset] & 0x80 != 0 { 2 } else { 1 };
+        }
+        if ext & 0x40 != 0 {
+            // L: TL0PICIDX
+            offset += 1;
+        }
+        if ext & 0x30 != 0 {
+            // T/K: TID/Y/KEYIDX
+            offset += 1;
+        }
+    }
+
+    // P bit of the VP8 payload header is 0 for keyframes
+    payload.len() > offset && payload[offset] & 0x01 == 0
+}
+
+/// VP9 payload descriptor (draft-ietf-payload-vp9 section 4.2), a keyframe is a picture that
+/// isn't inter-picture predicted, starting on its first packet in the base spatial layer
+fn is_vp9_keyframe(payload: &[u8]) -> bool {
+    if payload.is_empty() {
+        return false;
+    }
+
+    let inter_predicted = payload[0] & 0x40 != 0;
+    let start_of_frame = payload[0] & 0x08 != 0;
+    if inter_predicted || !start_of_frame {
+        return false;
+    }
+
+    let mut offset = 1;
+    if payload[0] & 0x80 != 0 {
+        // I: PictureID, 7 or 15 bits depending on M
+        if payload.len() <= offset {
+            return false;
+        }
+        offset += if payload[offset] & 0x80 != 0 { 2 } else { 1 };
+    }
+    if payload[0] & 0x20 != 0 {
+        // L: layer indices, only the base spatial layer starts a keyframe
+        if payload.len() <= offset {
+            return false;
+        }
+        let spatial_id = (payload[offset] >> 1) & 0x07;
+        return spatial_id == 0;
+    }
+
+    true
+}
+
+const H264_NALU_IDR: u8 = 5;
+const H264_NALU_SPS: u8 = 7;
+const H264_NALU_STAP_A: u8 = 24;
+const H264_NALU_FU_A: u8 = 28;
+
+/// H264 RTP payload (RFC 6184), a keyframe starts with an SPS or IDR slice
+fn is_h264_keyframe(payload: &[u8]) -> bool {
+    if payload.is_empty() {
+        return false;
+    }
+
+    let is_key_nalu = |t: u8| t == H264_NALU_IDR || t == H264_NALU_SPS;
+
+    match payload[0] & 0x1F {
+        H264_NALU_STAP_A => {
+            let mut offset = 1;
+            while offset + 2 < payload.len() {
+                let size = u16::from_be_bytes([payload[offset], payload[offset + 1]]) as usize;
+                offset += 2;
+                if is_key_nalu(payload[offset] & 0x1F) {
+                    return true;
+                }
+                offset += size;
+            }
+            false
+        }
+        H264_NALU_FU_A => {
+            // Only the fragment with the S bit set carries the start of the NALU
+            payload.len() > 1 && payload[1] & 0x80 != 0 && is_key_nalu(payload[1] & 0x1F)
+        }
+        t => is_key_nalu(t),
+    }
+}
+
+/// AV1 aggregation header (AV1 RTP payload format section 4.4), the N bit is set on the
+/// first packet of a coded video sequence which always starts with a keyframe
+fn is_av1_keyframe(payload: &[u8]) -> bool {
+    !payload.is_empty() && payload[0] & 0x08 != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // VP8 payload header of a keyframe (P bit clear, then the 0x9d012a start code) and of
+    // an interframe
+    const VP8_KEY: [u8; 6] = [0x50, 0x42, 0x00, 0x9d, 0x01, 0x2a];
+    const VP8_DELTA: [u8; 3] = [0x31, 0x10, 0x00];
+
+    fn check(parse: fn(&[u8]) -> bool, cases: &[(&[u8], bool)]) {
+        for (payload, keyframe) in cases {
+            assert_eq!(parse(payload), *keyframe, "payload {:02x?}", payload);
+        }
+    }
+
+    fn vp8(descriptor: &[u8], header: &[u8]) -> Vec<u8> {
+        [descriptor, header].concat()
+    }
+
+    #[test]
+    fn detects_vp8_keyframes() {
+        check(
+            is_vp8_keyframe,
+            &[
+                (&vp8(&[0x10], &VP8_KEY), true),
+                (&vp8(&[0x10], &VP8_DELTA), false),
+                // not the start of partition 0
+                (&vp8(&[0x00], &VP8_KEY), false),
+                (&vp8(&[0x11], &VP8_KEY), false),
+                // extended: 15 bit PictureID
+                (&vp8(&[0x90, 0x80, 0x81, 0x23], &VP8_KEY), true),
+                (&vp8(&[0x90, 0x80, 0x81, 0x23], &VP8_DELTA), false),
+                // extended: 7 bit PictureID, TL0PICIDX and TID/KEYIDX
+                (&vp8(&[0x90, 0xe0, 0x12, 0x05, 0x20], &VP8_KEY), true),
+                (&vp8(&[0x90, 0xe0, 0x12, 0x05, 0x20], &VP8_DELTA), false),
+                // truncated
+                (&[], false),
+                (&[0x10], false),
+                (&[0x90], false),
+                (&[0x90, 0x80], false),
+                (&[0x90, 0x80, 0x81, 0x23], false),
+                (&[0x90, 0xe0, 0x12, 0x05], false),
+            ],
+        );
+    }
+
+    #[test]
+    fn detects_vp9_keyframes() {
+        check(
+            is_vp9_keyframe,
+            &[
+                // I and B set, 7 bit PictureID
+                (&[0x88, 0x12, 0x00], true),
+                // P set: inter-picture predicted
+                (&[0xc8, 0x12, 0x00], false),
+                // not the start of a frame
+                (&[0x80, 0x12, 0x00], false),
+                // 15 bit PictureID
+                (&[0x88, 0x81, 0x23], true),
+                // layer indices, base and upper spatial layer
+                (&[0xa8, 0x12, 0x00, 0x00], true),
+                (&[0xa8, 0x12, 0x02, 0x00], false),
+                (&[0xe8, 0x12, 0x00, 0x00], false),
+                // truncated
+                (&[], false),
+                (&[0x88], false),
+                (&[0xa8, 0x12], false),
+                (&[0xa8, 0x81, 0x23], false),
+            ],
+        );
+    }
+
+    #[test]
+    fn detects_h264_keyframes() {
+        check(
+            is_h264_keyframe,
+            &[
+                // single NAL units: IDR slice, SPS, non-IDR slice, PPS
+                (&[0x65, 0x88, 0x84], true),
+                (&[0x67, 0x42, 0xc0], true),
+                (&[0x41, 0x9a, 0x02], false),
+                (&[0x68, 0xce, 0x3c], false),
+                // STAP-A of an access unit delimiter and an IDR slice
+                (
+                    &[0x78, 0x00, 0x02, 0x09, 0x10, 0x00, 0x02, 0x65, 0x88],
+                    true,
+                ),
+                // STAP-A of an access unit delimiter and a non-IDR slice
+                (
+                    &[0x78, 0x00, 0x02, 0x09, 0x10, 0x00, 0x02, 0x41, 0x9a],
+                    false,
+                ),
+                // FU-A of an IDR slice: start, continuation and end fragments
+                (&[0x7c, 0x85, 0x88, 0x84], true),
+                (&[0x7c, 0x05, 0x88, 0x84], false),
+                (&[0x7c, 0x45, 0x88, 0x84], false),
+                // FU-A start of a non-IDR slice
+                (&[0x5c, 0x81, 0x9a], false),
+                // truncated
+                (&[], false),
+                (&[0x78], false),
+                (&[0x78, 0x00], false),
+                (&[0x78, 0x00, 0x05], false),
+                (&[0x78, 0x00, 0x05, 0x09], false),
+                (&[0x7c], false),
+            ],
+        );
+    }
+
+    #[test]
+    fn detects_av1_keyframes() {
+        check(
+            is_av1_keyframe,
+            &[
+                // W=1 with and without N
+                (&[0x18, 0x0a, 0x0b], true),
+                (&[0x10, 0x32, 0x10], false),
+                // Z and Y set, continuing fragments
+                (&[0xd0, 0x00], false),
+                (&[], false),
+            ],
+        );
+    }
+
+    #[test]
+    fn dispatches_on_mime_type() {
+        let key = vp8(&[0x10], &VP8_KEY);
+        assert_eq!(is_keyframe("video/VP8", &key), Some(true));
+        assert_eq!(is_keyframe(MIME_TYPE_VP9, &[0xc8, 0x12]), Some(false));
+        assert_eq!(is_keyframe(MIME_TYPE_H264, &[0x65]), Some(true));
+        assert_eq!(is_keyframe(MIME_TYPE_AV1, &[]), Some(false));
+        assert_eq!(is_keyframe("audio/opus", &key), None);
+    }
+}
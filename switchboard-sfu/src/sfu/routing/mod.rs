@@ -1,5 +1,10 @@
+mod cache;
+mod keyframe;
+mod munger;
 mod router;
+mod stats;
 mod subscriber;
 
 pub use router::*;
+pub use stats::MediaTrackRouterStats;
 pub use subscriber::*;
@@ -0,0 +1,276 @@
+use std::time::Instant;
+use webrtc::rtp::header::Header;
+
+// Based on the sequence validation in RFC 3550 appendix A.1, with a wider misorder window
+// because retransmissions from the publisher arrive well behind the highest sequence number
+const MAX_DROPOUT: u16 = 3000;
+const MAX_MISORDER: u16 = 1000;
+// extended sequence numbers start a cycle in, so stragglers from before the base never wrap
+// below zero
+const FIRST_CYCLE: u64 = 1 << 16;
+
+/// RtpMunger rewrites sequence numbers and timestamps of the packets a MediaTrackSubscriber
+/// forwards so the receiver sees one continuous stream.
+/// Offsets are constant while the source is unchanged (gaps from loss are kept so receivers
+/// can NACK them) and are rebased when the source switches (simulcast layer, publisher restart).
+/// The SSRC itself is rewritten by the track binding when the packet is written.
+pub(super) struct RtpMunger {
+    clock_rate: u32,
+    state: Option<MungerState>,
+}
+
+struct MungerState {
+    source_ssrc: u32,
+    // extended (cycle counted) sequence numbers, so streams longer than 65536 packets still
+    // compare correctly
+    // first source sequence number of the current source, older packets are dropped
+    base_seq: u64,
+    highest_seq: u64,
+
+    seq_offset: u16,
+    ts_offset: u32,
+
+    last_seq: u16,
+    last_ts: u32,
+    last_time: Instant,
+}
+
+impl RtpMunger {
+    pub fn new(clock_rate: u32) -> RtpMunger {
+        RtpMunger {
+            clock_rate,
+            state: None,
+        }
+    }
+
+    /// Rewrites the header in place, returns false if the packet should be dropped
+    /// (duplicates and stragglers from a previous source)
+    pub fn process(&mut self, header: &mut Header, now: Instant) -> bool {
+        let seq = header.sequence_number;
+        let ts = header.timestamp;
+
+        let state = match &mut self.state {
+            Some(state) if state.source_ssrc == header.ssrc => state,
+            _ => {
+                self.rebase(header, now);
+                return true;
+            }
+        };
+
+        let diff = seq.wrapping_sub(state.highest_seq as u16);
+        if diff == 0 {
+            return false;
+        } else if diff < MAX_DROPOUT {
+            // in order, possibly with a gap from loss
+            state.highest_seq += diff as u64;
+        } else if diff <= u16::MAX - MAX_MISORDER {
+            // sequence jumped too far to be loss, the source restarted
+            self.rebase(header, now);
+            return true;
+        } else if state.highest_seq - (diff.wrapping_neg() as u64) < state.base_seq {
+            // reordered but from before the current source started
+            return false;
+        }
+
+        header.sequence_number = seq.wrapping_add(state.seq_offset);
+        header.timestamp = ts.wrapping_add(state.ts_offset);
+
+        if diff < MAX_DROPOUT {
+            state.last_seq = header.sequence_number;
+            state.last_ts = header.timestamp;
+            state.last_time = now;
+        }
+
+        true
+    }
+
+    /// Maps an outgoing sequence number back to the source packet so it can be retransmitted
+    /// Returns (source ssrc, source sequence number, timestamp offset), only packets of the
+    /// current source up to the highest one forwarded can be mapped
+    pub fn lookup(&self, sequence_number: u16) -> Option<(u32, u16, u32)> {
+        let state = self.state.as_ref()?;
+        let seq = sequence_number.wrapping_sub(state.seq_offset);
+
+        // the most recent packet with this sequence number
+        let behind = (state.highest_seq as u16).wrapping_sub(seq) as u64;
+        if behind > state.highest_seq - state.base_seq {
+            return None;
+        }
+
+        Some((state.source_ssrc, seq, state.ts_offset))
+    }
+
+    /// Maps the source so that this packet directly follows the last one written
+    fn rebase(&mut self, header: &mut Header, now: Instant) {
+        let seq = header.sequence_number;
+        let ts = header.timestamp;
+
+        let (out_seq, out_ts) = match &self.state {
+            None => (seq, ts),
+            Some(state) => {
+                // advance the timestamp by the wall clock time since the last packet
+                let elapsed = now.saturating_duration_since(state.last_time);
+                let ticks = (elapsed.as_nanos() * self.clock_rate as u128 / 1_000_000_000) as u32;
+                (
+                    state.last_seq.wrapping_add(1),
+                    state.last_ts.wrapping_add(ticks.max(1)),
+                )
+            }
+        };
+
+        self.state = Some(MungerState {
+            source_ssrc: header.ssrc,
+            base_seq: FIRST_CYCLE + seq as u64,
+            highest_seq: FIRST_CYCLE + seq as u64,
+            seq_offset: out_seq.wrapping_sub(seq),
+            ts_offset: out_ts.wrapping_sub(ts),
+            last_seq: out_seq,
+            last_ts: out_ts,
+            last_time: now,
+        });
+
+        header.sequence_number = out_seq;
+        header.timestamp = out_ts;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn header(ssrc: u32, seq: u16, ts: u32) -> Header {
+        Header {
+            ssrc,
+            sequence_number: seq,
+            timestamp: ts,
+            ..Default::default()
+        }
+    }
+
+    fn munge(m: &mut RtpMunger, ssrc: u32, seq: u16, ts: u32, now: Instant) -> Option<(u16, u32)> {
+        let mut h = header(ssrc, seq, ts);
+        if m.process(&mut h, now) {
+            Some((h.sequence_number, h.timestamp))
+        } else {
+            None
+        }
+    }
+
+    #[test]
+    fn keeps_gaps_from_loss() {
+        let now = Instant::now();
+        let mut m = RtpMunger::new(90000);
+
+        assert_eq!(munge(&mut m, 1, 100, 1000, now), Some((100, 1000)));
+        assert_eq!(munge(&mut m, 1, 101, 4000, now), Some((101, 4000)));
+        // 102 and 103 lost
+        assert_eq!(munge(&mut m, 1, 104, 13000, now), Some((104, 13000)));
+    }
+
+    #[test]
+    fn maps_reordered_packets() {
+        let now = Instant::now();
+        let mut m = RtpMunger::new(90000);
+
+        assert_eq!(munge(&mut m, 1, 10, 0, now), Some((10, 0)));
+        assert_eq!(munge(&mut m, 1, 12, 6000, now), Some((12, 6000)));
+        assert_eq!(munge(&mut m, 1, 11, 3000, now), Some((11, 3000)));
+        assert_eq!(munge(&mut m, 1, 13, 9000, now), Some((13, 9000)));
+    }
+
+    #[test]
+    fn drops_duplicates() {
+        let now = Instant::now();
+        let mut m = RtpMunger::new(90000);
+
+        assert!(munge(&mut m, 1, 10, 0, now).is_some());
+        assert!(munge(&mut m, 1, 10, 0, now).is_none());
+    }
+
+    #[test]
+    fn handles_wraparound() {
+        let now = Instant::now();
+        let mut m = RtpMunger::new(90000);
+
+        assert_eq!(
+            munge(&mut m, 1, u16::MAX - 1, u32::MAX - 3000, now),
+            Some((u16::MAX - 1, u32::MAX - 3000))
+        );
+        assert_eq!(
+            munge(&mut m, 1, u16::MAX, u32::MAX, now),
+            Some((u16::MAX, u32::MAX))
+        );
+        assert_eq!(munge(&mut m, 1, 0, 2999, now), Some((0, 2999)));
+        // reordered across the wrap
+        assert_eq!(munge(&mut m, 1, 2, 8999, now), Some((2, 8999)));
+        assert_eq!(munge(&mut m, 1, 1, 5999, now), Some((1, 5999)));
+    }
+
+    #[test]
+    fn rebases_on_source_change() {
+        let now = Instant::now();
+        let mut m = RtpMunger::new(90000);
+
+        assert_eq!(munge(&mut m, 1, 500, 90000, now), Some((500, 90000)));
+        assert_eq!(munge(&mut m, 1, 501, 93000, now), Some((501, 93000)));
+
+        // switching layers continues the output 10ms later
+        let later = now + Duration::from_millis(10);
+        assert_eq!(munge(&mut m, 2, 7000, 5, later), Some((502, 93900)));
+        assert_eq!(munge(&mut m, 2, 7002, 6005, later), Some((504, 99900)));
+
+        // stragglers from before the switch are dropped
+        assert!(munge(&mut m, 2, 6999, 0, later).is_none());
+        // but the new source still maps its own reordered packets
+        assert_eq!(munge(&mut m, 2, 7001, 3005, later), Some((503, 96900)));
+    }
+
+    #[test]
+    fn looks_up_source_of_forwarded_packets() {
+        let now = Instant::now();
+        let mut m = RtpMunger::new(90000);
+
+        assert_eq!(munge(&mut m, 1, 10, 100, now), Some((10, 100)));
+        assert_eq!(munge(&mut m, 1, 12, 200, now), Some((12, 200)));
+        assert_eq!(m.lookup(11), Some((1, 11, 0)));
+        assert_eq!(m.lookup(13), None);
+
+        let later = now + Duration::from_millis(10);
+        assert_eq!(munge(&mut m, 2, 500, 1000, later), Some((13, 1100)));
+        assert_eq!(munge(&mut m, 2, 502, 4000, later), Some((15, 4100)));
+        assert_eq!(m.lookup(14), Some((2, 501, 100)));
+        // packets of the previous source can't be mapped anymore
+        assert_eq!(m.lookup(11), None);
+    }
+
+    #[test]
+    fn rebases_on_sequence_jump() {
+        let now = Instant::now();
+        let mut m = RtpMunger::new(48000);
+
+        assert_eq!(munge(&mut m, 1, 100, 0, now), Some((100, 0)));
+        let later = now + Duration::from_millis(20);
+        assert_eq!(munge(&mut m, 1, 40000, 123456, later), Some((101, 960)));
+        assert_eq!(munge(&mut m, 1, 40001, 124416, later), Some((102, 1920)));
+    }
+
+    #[test]
+    fn maps_packets_after_many_cycles() {
+        let now = Instant::now();
+        let mut m = RtpMunger::new(90000);
+
+        // one full cycle and then some, skipping the packet sent at 65530
+        for i in 0..65540u32 {
+            let seq = i as u16;
+            if i != 65530 {
+                assert_eq!(munge(&mut m, 1, seq, i, now), Some((seq, i)));
+            }
+        }
+
+        // arrives late, 10 packets behind the highest
+        assert_eq!(munge(&mut m, 1, 65530, 65530, now), Some((65530, 65530)));
+        assert_eq!(m.lookup(65530), Some((1, 65530, 0)));
+        assert_eq!(m.lookup(3), Some((1, 3, 0)));
+    }
+}
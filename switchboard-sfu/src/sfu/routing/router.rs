@@ -3,85 +3,278 @@ use enclose::enc;
 use futures::StreamExt;
 use futures_channel::{mpsc, oneshot};
 use log::*;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::broadcast;
+use webrtc::rtcp;
+use webrtc::rtcp::payload_feedbacks::full_intra_request::{FirEntry, FullIntraRequest};
 use webrtc::rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication;
+use webrtc::rtcp::transport_feedbacks::transport_layer_nack::{
+    nack_pairs_from_sequence_numbers, TransportLayerNack,
+};
 use webrtc::rtp;
 use webrtc::rtp_transceiver::rtp_receiver::RTCRtpReceiver;
+use webrtc::rtp_transceiver::RTCPFeedback;
 use webrtc::track::track_remote::TrackRemote;
 
+use super::cache::{PacketCache, PacketCacheHandle};
+use super::stats::RouterCounters;
 use super::*;
 use crate::sfu::peer;
 
+/// Router ids are unique within a session: "<publisher peer id>:<transceiver mid>"
 pub type Id = String;
+/// App-defined key/value labels of a published track (eg. source=screen)
+pub type TrackLabels = HashMap<String, String>;
 pub type MediaTrackRouterHandle = Arc<Mutex<MediaTrackRouter>>;
 
+/// MediaTrackRouterConfig tunes how a MediaTrackRouter talks to the publisher
+#[derive(Clone, Debug)]
+pub struct MediaTrackRouterConfig {
+    /// Keyframe requests (PLI/FIR) from all subscribers are merged into at most one
+    /// request per layer within this interval
+    pub keyframe_request_interval: Duration,
+}
+
+impl Default for MediaTrackRouterConfig {
+    fn default() -> MediaTrackRouterConfig {
+        MediaTrackRouterConfig {
+            keyframe_request_interval: Duration::from_millis(500),
+        }
+    }
+}
+
+/// LayerPacket is an RTP packet tagged with the simulcast layer (rid) it was received on
+/// Tracks that aren't simulcast use an empty rid
+#[derive(Clone)]
+pub(super) struct LayerPacket {
+    pub rid: Arc<str>,
+    pub packet: rtp::packet::Packet,
+}
+
 /// MediaTrackRouter receives RTP from a TrackRemote and can generate MediaTrackSubscribers to
 /// write the track to multiple other peer connections
+/// Simulcast tracks arrive as one TrackRemote per rid, these are grouped as layers of a single router
 pub struct MediaTrackRouter {
     pub id: Id,
+    /// Peer that published this track
+    pub publisher: peer::Id,
+    /// Labels the publisher attached to this track in its offer
+    pub labels: TrackLabels,
     track_remote: Arc<TrackRemote>,
 
+    // rid -> ssrc for every layer of this track
+    layers: Arc<Mutex<HashMap<String, u32>>>,
+    // ssrc -> recent packets, used to answer subscriber NACKs
+    caches: Arc<Mutex<HashMap<u32, PacketCacheHandle>>>,
+    counters: Arc<RouterCounters>,
+    active_layers: Arc<AtomicUsize>,
+    closed_tx: Arc<Mutex<Option<oneshot::Sender<bool>>>>,
+
     event_tx: mpsc::Sender<MediaTrackSubscriberEvent>,
-    packet_sender: broadcast::Sender<rtp::packet::Packet>,
+    packet_sender: broadcast::Sender<LayerPacket>,
     _rtp_receiver: Arc<RTCRtpReceiver>,
 }
 
 impl MediaTrackRouter {
     pub async fn new(
+        id: Id,
+        publisher: peer::Id,
+        labels: TrackLabels,
         track_remote: Arc<TrackRemote>,
         rtp_receiver: Arc<RTCRtpReceiver>,
         rtcp_writer: peer::RtcpWriter,
+        config: MediaTrackRouterConfig,
     ) -> (MediaTrackRouterHandle, oneshot::Receiver<bool>) {
         let (pkt_tx, _pkt_rx) = broadcast::channel(512);
         let (evt_tx, evt_rx) = mpsc::channel(32);
+        let (closed_tx, closed_rx) = oneshot::channel();
+
+        let layers = Arc::new(Mutex::new(HashMap::new()));
+        let counters = Arc::new(RouterCounters::default());
+        let keyframe_requests = KeyframeRequests::new(&track_remote, &config);
+        tokio::spawn(enc!((layers, counters) async move {
+            MediaTrackRouter::rtcp_event_loop(layers, counters, keyframe_requests, evt_rx, rtcp_writer).await
+        }));
 
-        let media_ssrc = track_remote.ssrc();
+        let router = MediaTrackRouter {
+            id,
+            publisher,
+            labels,
+            track_remote: track_remote.clone(),
+            layers,
+            caches: Arc::new(Mutex::new(HashMap::new())),
+            counters,
+            active_layers: Arc::new(AtomicUsize::new(0)),
+            closed_tx: Arc::new(Mutex::new(Some(closed_tx))),
+            packet_sender: pkt_tx,
+            _rtp_receiver: rtp_receiver,
+            event_tx: evt_tx,
+        };
+        router.add_layer(track_remote).await;
+
+        (Arc::new(Mutex::new(router)), closed_rx)
+    }
+
+    /// Adds another simulcast layer (TrackRemote with the same id but a different rid)
+    /// The router is closed once every layer has stopped receiving RTP
+    pub async fn add_layer(&self, track: Arc<TrackRemote>) {
+        let rid = track.rid().to_owned();
+        debug!(
+            "MediaTrackRouter(id={}) adding layer rid={} ssrc={}",
+            self.id,
+            rid,
+            track.ssrc()
+        );
+
+        let cache = Arc::new(Mutex::new(PacketCache::new()));
+        self.layers.lock().await.insert(rid.clone(), track.ssrc());
+        self.caches.lock().await.insert(track.ssrc(), cache.clone());
+        self.active_layers.fetch_add(1, Ordering::SeqCst);
+
+        let active_layers = self.active_layers.clone();
+        let closed_tx = self.closed_tx.clone();
+        let packet_sender = self.packet_sender.clone();
         tokio::spawn(async move {
-            MediaTrackRouter::rtcp_event_loop(media_ssrc, evt_rx, rtcp_writer).await
+            MediaTrackRouter::rtp_event_loop(track, rid.into(), cache, packet_sender).await;
+
+            if active_layers.fetch_sub(1, Ordering::SeqCst) == 1 {
+                if let Some(closed_tx) = closed_tx.lock().await.take() {
+                    let _ = closed_tx.send(true);
+                }
+            }
         });
+    }
 
-        let (closed_tx, closed_rx) = oneshot::channel();
-        tokio::spawn(enc!((pkt_tx, track_remote) async move {
-            MediaTrackRouter::rtp_event_loop(track_remote, pkt_tx).await;
-            let _ = closed_tx.send(true);
-        }));
+    /// Returns the rids of every layer currently routed (a single empty rid without simulcast)
+    pub async fn layers(&self) -> Vec<String> {
+        self.layers.lock().await.keys().cloned().collect()
+    }
 
-        (
-            Arc::new(Mutex::new(MediaTrackRouter {
-                id: track_remote.id(),
-                track_remote,
-                packet_sender: pkt_tx,
-                _rtp_receiver: rtp_receiver,
-                event_tx: evt_tx,
-            })),
-            closed_rx,
-        )
+    /// Stream id subscribers see, namespaced by the publisher so it can't collide with
+    /// streams of other peers
+    pub fn stream_id(&self) -> String {
+        format!("{}:{}", self.publisher, self.track_remote.stream_id())
+    }
+
+    /// Track id as sent by the publisher
+    pub fn publisher_track_id(&self) -> String {
+        self.track_remote.id()
+    }
+
+    /// Stream id as sent by the publisher
+    pub fn publisher_stream_id(&self) -> String {
+        self.track_remote.stream_id()
+    }
+
+    /// Mime type of the routed track's codec
+    pub fn codec(&self) -> String {
+        self.track_remote.codec().capability.mime_type
+    }
+
+    /// Kind of the routed track ("audio" or "video")
+    pub fn kind(&self) -> String {
+        self.track_remote.kind().to_string()
     }
 
     pub async fn add_subscriber(&self) -> MediaTrackSubscriber {
         trace!("MediaTrackRouter adding new subscriber");
 
         let event_tx = self.event_tx.clone();
-        MediaTrackSubscriber::new(&self.track_remote, self.packet_sender.subscribe(), event_tx)
-            .await
+        MediaTrackSubscriber::new(
+            &self.track_remote,
+            self.id.clone(),
+            self.stream_id(),
+            self.packet_sender.subscribe(),
+            event_tx,
+            self.caches.clone(),
+            self.counters.clone(),
+        )
+        .await
+    }
+
+    pub async fn stats(&self) -> MediaTrackRouterStats {
+        MediaTrackRouterStats::new(
+            self.id.clone(),
+            self.layers().await,
+            self.packet_sender.receiver_count(),
+            &self.counters,
+        )
     }
 
     async fn rtcp_event_loop(
-        media_ssrc: u32,
+        layers: Arc<Mutex<HashMap<String, u32>>>,
+        counters: Arc<RouterCounters>,
+        mut keyframe_requests: KeyframeRequests,
         mut event_rx: mpsc::Receiver<MediaTrackSubscriberEvent>,
         mut rtcp_writer: peer::RtcpWriter,
     ) {
-        while let Some(event) = event_rx.next().await {
-            match event {
-                MediaTrackSubscriberEvent::PictureLossIndication => {
-                    trace!("MediaTrackRouter forwarding PLI from MediaTrackSubscriber");
-                    rtcp_writer
-                        .try_send(Box::new(PictureLossIndication {
+        let mut ticker = tokio::time::interval(keyframe_requests.interval);
+
+        loop {
+            tokio::select! {
+                event = event_rx.next() => match event {
+                    Some(MediaTrackSubscriberEvent::KeyframeRequest { rid }) => {
+                        counters.keyframe_requests_received.fetch_add(1, Ordering::Relaxed);
+
+                        let layers = layers.lock().await;
+                        match rid {
+                            Some(rid) => match layers.get(&rid) {
+                                Some(ssrc) => keyframe_requests.request(*ssrc),
+                                None => {
+                                    debug!("MediaTrackRouter dropping keyframe request for unknown layer={}", rid);
+                                }
+                            },
+                            None => layers.values().for_each(|ssrc| keyframe_requests.request(*ssrc)),
+                        }
+                    }
+                    Some(MediaTrackSubscriberEvent::Nack {
+                        ssrc,
+                        sequence_numbers,
+                    }) => {
+                        trace!(
+                            "MediaTrackRouter forwarding NACK ssrc={} seq={:?}",
+                            ssrc,
+                            sequence_numbers
+                        );
+                        counters
+                            .nacks_forwarded
+                            .fetch_add(sequence_numbers.len() as u64, Ordering::Relaxed);
+
+                        let nack = TransportLayerNack {
                             sender_ssrc: 0,
-                            media_ssrc,
-                        }))
-                        .expect("Couldn't forward PLI to MediaTrackRouter");
+                            media_ssrc: ssrc,
+                            nacks: nack_pairs_from_sequence_numbers(&sequence_numbers),
+                        };
+                        if let Err(err) = rtcp_writer.try_send(Box::new(nack)) {
+                            debug!("MediaTrackRouter couldn't forward NACK: {}", err);
+                        }
+                    }
+                    None => break,
+                },
+                _ = ticker.tick() => {}
+            }
+
+            // Send any keyframe requests whose interval has elapsed, a full RTCP channel
+            // leaves them pending for the next tick
+            let now = Instant::now();
+            for media_ssrc in keyframe_requests.due(now) {
+                trace!("MediaTrackRouter requesting keyframe ssrc={}", media_ssrc);
+                match rtcp_writer.try_send(keyframe_requests.packet(media_ssrc)) {
+                    Ok(_) => {
+                        keyframe_requests.sent(media_ssrc, now);
+                        counters
+                            .keyframe_requests_sent
+                            .fetch_add(1, Ordering::Relaxed);
+                    }
+                    Err(err) => {
+                        debug!("MediaTrackRouter couldn't request keyframe: {}", err);
+                        if err.is_disconnected() {
+                            break;
+                        }
+                    }
                 }
             }
         }
@@ -89,27 +282,20 @@ impl MediaTrackRouter {
     }
 
     // Process RTCP & RTP packets for this track
-    pub async fn rtp_event_loop(
+    async fn rtp_event_loop(
         track: Arc<TrackRemote>,
-        packet_sender: broadcast::Sender<rtp::packet::Packet>,
+        rid: Arc<str>,
+        cache: PacketCacheHandle,
+        packet_sender: broadcast::Sender<LayerPacket>,
     ) {
         debug!(
-            "MediaTrackRouter has started, of type {}: {}",
+            "MediaTrackRouter has started, of type {}: {} rid={}",
             track.payload_type(),
-            track.codec().capability.mime_type
+            track.codec().capability.mime_type,
+            rid
         );
 
-        let mut last_timestamp = 0;
-        while let Ok((mut rtp, _attr)) = track.read_rtp().await {
-            // Change the timestamp to only be the delta
-            let old_timestamp = rtp.header.timestamp;
-            if last_timestamp == 0 {
-                rtp.header.timestamp = 0
-            } else {
-                rtp.header.timestamp -= last_timestamp;
-            }
-            last_timestamp = old_timestamp;
-
+        while let Ok((rtp, _attr)) = track.read_rtp().await {
             trace!(
                 "MediaTrackRouter received RTP ssrc={} seq={} timestamp={}",
                 rtp.header.ssrc,
@@ -117,9 +303,15 @@ impl MediaTrackRouter {
                 rtp.header.timestamp
             );
 
+            cache.lock().await.push(&rtp);
+
             // Send packet to broadcast channel
             if packet_sender.receiver_count() > 0 {
-                if let Err(e) = packet_sender.send(rtp) {
+                let packet = LayerPacket {
+                    rid: rid.clone(),
+                    packet: rtp,
+                };
+                if let Err(e) = packet_sender.send(packet) {
                     error!("MediaTrackRouter failed to broadcast RTP: {}", e);
                 }
             } else {
@@ -128,9 +320,192 @@ impl MediaTrackRouter {
         }
 
         debug!(
-            "MediaTrackRouter has ended, of type {}: {}",
+            "MediaTrackRouter has ended, of type {}: {} rid={}",
             track.payload_type(),
-            track.codec().capability.mime_type
+            track.codec().capability.mime_type,
+            rid
         );
     }
 }
+
+/// KeyframeRequests merges keyframe requests from every subscriber of a router so the
+/// publisher sees at most one PLI (or FIR) per layer per interval
+struct KeyframeRequests {
+    interval: Duration,
+    // the publisher only negotiated `ccm fir`, not `nack pli`
+    use_fir: bool,
+    pending: HashSet<u32>,
+    last_sent: HashMap<u32, Instant>,
+    fir_sequence_numbers: HashMap<u32, u8>,
+}
+
+impl KeyframeRequests {
+    fn new(track: &TrackRemote, config: &MediaTrackRouterConfig) -> KeyframeRequests {
+        KeyframeRequests::with_feedback(
+            &track.codec().capability.rtcp_feedback,
+            config.keyframe_request_interval,
+        )
+    }
+
+    fn with_feedback(feedback: &[RTCPFeedback], interval: Duration) -> KeyframeRequests {
+        let has_feedback = |typ: &str, parameter: &str| {
+            feedback
+                .iter()
+                .any(|fb| fb.typ == typ && fb.parameter == parameter)
+        };
+
+        KeyframeRequests {
+            // tokio intervals can't be zero
+            interval: interval.max(Duration::from_millis(1)),
+            use_fir: has_feedback("ccm", "fir") && !has_feedback("nack", "pli"),
+            pending: HashSet::new(),
+            last_sent: HashMap::new(),
+            fir_sequence_numbers: HashMap::new(),
+        }
+    }
+
+    fn request(&mut self, media_ssrc: u32) {
+        self.pending.insert(media_ssrc);
+    }
+
+    fn due(&self, now: Instant) -> Vec<u32> {
+        self.pending
+            .iter()
+            .filter(|ssrc| match self.last_sent.get(ssrc) {
+                Some(last) => now.saturating_duration_since(*last) >= self.interval,
+                None => true,
+            })
+            .cloned()
+            .collect()
+    }
+
+    fn sent(&mut self, media_ssrc: u32, now: Instant) {
+        self.pending.remove(&media_ssrc);
+        self.last_sent.insert(media_ssrc, now);
+        if self.use_fir {
+            let sequence_number = self.fir_sequence_numbers.entry(media_ssrc).or_insert(0);
+            *sequence_number = sequence_number.wrapping_add(1);
+        }
+    }
+
+    fn packet(&self, media_ssrc: u32) -> Box<dyn rtcp::packet::Packet + Send + Sync> {
+        if !self.use_fir {
+            return Box::new(PictureLossIndication {
+                sender_ssrc: 0,
+                media_ssrc,
+            });
+        }
+
+        // every new FIR request increments the sequence number, a retry of an unsent one
+        // keeps it (RFC 5104 section 4.3.1.1), sent() counts it up
+        let sequence_number = self
+            .fir_sequence_numbers
+            .get(&media_ssrc)
+            .copied()
+            .unwrap_or(0)
+            .wrapping_add(1);
+        // the media ssrc of the common header SHALL be 0, the FCI entry names the source
+        Box::new(FullIntraRequest {
+            sender_ssrc: 0,
+            media_ssrc: 0,
+            fir: vec![FirEntry {
+                ssrc: media_ssrc,
+                sequence_number,
+            }],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL: Duration = Duration::from_millis(500);
+
+    fn feedback(typ: &str, parameter: &str) -> RTCPFeedback {
+        RTCPFeedback {
+            typ: typ.to_owned(),
+            parameter: parameter.to_owned(),
+        }
+    }
+
+    fn fir(requests: &KeyframeRequests, ssrc: u32) -> Option<FullIntraRequest> {
+        let packet = requests.packet(ssrc);
+        packet.as_any().downcast_ref::<FullIntraRequest>().cloned()
+    }
+
+    #[test]
+    fn coalesces_requests_within_the_interval() {
+        let now = Instant::now();
+        let mut requests = KeyframeRequests::with_feedback(&[], INTERVAL);
+
+        requests.request(1);
+        requests.request(1);
+        assert_eq!(requests.due(now), vec![1]);
+        requests.sent(1, now);
+        assert!(requests.due(now).is_empty());
+
+        // more requests within the interval wait for it
+        requests.request(1);
+        requests.request(1);
+        assert!(requests.due(now + INTERVAL / 2).is_empty());
+        assert_eq!(requests.due(now + INTERVAL), vec![1]);
+        requests.sent(1, now + INTERVAL);
+        assert!(requests.due(now + INTERVAL * 3).is_empty());
+    }
+
+    #[test]
+    fn throttles_each_ssrc_separately() {
+        let now = Instant::now();
+        let mut requests = KeyframeRequests::with_feedback(&[], INTERVAL);
+
+        requests.request(1);
+        requests.sent(1, now);
+        requests.request(1);
+        requests.request(2);
+        assert_eq!(requests.due(now), vec![2]);
+    }
+
+    #[test]
+    fn picks_pli_or_fir_from_feedback() {
+        let pli = [feedback("nack", "pli")];
+        let fir_only = [feedback("ccm", "fir")];
+        let both = [feedback("ccm", "fir"), feedback("nack", "pli")];
+
+        for (feedback, use_fir) in [
+            (&pli[..], false),
+            (&fir_only, true),
+            (&both, false),
+            (&[], false),
+        ] {
+            let requests = KeyframeRequests::with_feedback(feedback, INTERVAL);
+            let packet = requests.packet(7);
+            assert_eq!(
+                packet.as_any().is::<FullIntraRequest>(),
+                use_fir,
+                "{:?}",
+                feedback
+            );
+            assert_eq!(packet.as_any().is::<PictureLossIndication>(), !use_fir);
+        }
+    }
+
+    #[test]
+    fn counts_fir_sequence_numbers_on_send() {
+        let now = Instant::now();
+        let mut requests = KeyframeRequests::with_feedback(&[feedback("ccm", "fir")], INTERVAL);
+
+        let first = fir(&requests, 7).unwrap();
+        assert_eq!(first.media_ssrc, 0);
+        assert_eq!(first.fir[0].ssrc, 7);
+        assert_eq!(first.fir[0].sequence_number, 1);
+
+        // a retry of an unsent request keeps its sequence number
+        assert_eq!(fir(&requests, 7).unwrap().fir[0].sequence_number, 1);
+
+        requests.request(7);
+        requests.sent(7, now);
+        assert_eq!(fir(&requests, 7).unwrap().fir[0].sequence_number, 2);
+        assert_eq!(fir(&requests, 8).unwrap().fir[0].sequence_number, 1);
+    }
+}
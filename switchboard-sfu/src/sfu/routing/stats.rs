@@ -0,0 +1,50 @@
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters shared between a MediaTrackRouter and its MediaTrackSubscribers
+#[derive(Default)]
+pub(super) struct RouterCounters {
+    pub nacks_received: AtomicU64,
+    pub retransmitted_packets: AtomicU64,
+    pub nacks_forwarded: AtomicU64,
+    pub keyframe_requests_received: AtomicU64,
+    pub keyframe_requests_sent: AtomicU64,
+}
+
+/// Snapshot of a MediaTrackRouter's counters
+#[derive(Serialize, Clone, Debug)]
+pub struct MediaTrackRouterStats {
+    pub id: String,
+    pub layers: Vec<String>,
+    pub subscribers: usize,
+    /// sequence numbers NACKed by subscribers
+    pub nacks_received: u64,
+    /// NACKed packets answered from the router's packet cache
+    pub retransmitted_packets: u64,
+    /// NACKed packets missing from the cache and requested from the publisher
+    pub nacks_forwarded: u64,
+    /// PLI/FIR requests from subscribers (including layer switches and new subscribers)
+    pub keyframe_requests_received: u64,
+    /// PLI/FIR requests sent to the publisher after coalescing
+    pub keyframe_requests_sent: u64,
+}
+
+impl MediaTrackRouterStats {
+    pub(super) fn new(
+        id: String,
+        layers: Vec<String>,
+        subscribers: usize,
+        counters: &RouterCounters,
+    ) -> MediaTrackRouterStats {
+        MediaTrackRouterStats {
+            id,
+            layers,
+            subscribers,
+            nacks_received: counters.nacks_received.load(Ordering::Relaxed),
+            retransmitted_packets: counters.retransmitted_packets.load(Ordering::Relaxed),
+            nacks_forwarded: counters.nacks_forwarded.load(Ordering::Relaxed),
+            keyframe_requests_received: counters.keyframe_requests_received.load(Ordering::Relaxed),
+            keyframe_requests_sent: counters.keyframe_requests_sent.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -1,55 +1,179 @@
-//use async_mutex::Mutex;
-use anyhow::Result;
+use async_mutex::Mutex;
+use async_trait::async_trait;
 use enclose::enc;
+use futures::StreamExt;
 use futures_channel::mpsc;
 use log::*;
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use tokio::sync::broadcast;
+use std::time::Instant;
+use tokio::sync::{broadcast, watch, Notify};
 use webrtc::peer_connection::RTCPeerConnection;
 use webrtc::rtcp;
 use webrtc::rtp;
+use webrtc::rtp_transceiver::rtp_codec::{RTCRtpCodecParameters, RTPCodecType};
 use webrtc::rtp_transceiver::rtp_sender::RTCRtpSender;
 use webrtc::track::track_local::track_local_static_rtp::TrackLocalStaticRTP;
-use webrtc::track::track_local::{TrackLocal, TrackLocalWriter};
+use webrtc::track::track_local::{TrackLocal, TrackLocalContext, TrackLocalWriter};
 use webrtc::track::track_remote::TrackRemote;
-use webrtc::Error;
+
+use super::cache::PacketCacheHandle;
+use super::keyframe;
+use super::munger::RtpMunger;
+use super::router::LayerPacket;
+use super::stats::RouterCounters;
+use crate::{Error, Result};
 
 pub(super) enum MediaTrackSubscriberEvent {
-    PictureLossIndication,
+    /// None asks for a keyframe on every layer
+    KeyframeRequest { rid: Option<String> },
+    Nack {
+        ssrc: u32,
+        sequence_numbers: Vec<u16>,
+    },
+}
+
+/// MediaTrackSubscriberHandle controls a running MediaTrackSubscriber from the subscribing Peer
+#[derive(Clone)]
+pub struct MediaTrackSubscriberHandle {
+    target_layer: Arc<watch::Sender<Option<String>>>,
+    stop: Arc<Notify>,
+}
+
+impl MediaTrackSubscriberHandle {
+    /// Chooses which simulcast layer (rid) is forwarded
+    /// None lets the subscriber lock onto the first layer that delivers a keyframe
+    pub fn select_layer(&self, rid: Option<String>) {
+        self.target_layer.send_replace(rid);
+    }
+
+    /// Stops forwarding, rtp_event_loop returns
+    pub fn stop(&self) {
+        self.stop.notify_one();
+    }
+}
+
+/// TrackLocalStaticRTP that tells when a peer connection binds it, packets written before that
+/// go nowhere
+struct BindingTrack {
+    rtp: TrackLocalStaticRTP,
+    bound: watch::Sender<bool>,
+}
+
+#[async_trait]
+impl TrackLocal for BindingTrack {
+    async fn bind(&self, t: &TrackLocalContext) -> webrtc::error::Result<RTCRtpCodecParameters> {
+        let codec = self.rtp.bind(t).await?;
+        self.bound.send_replace(true);
+        Ok(codec)
+    }
+
+    async fn unbind(&self, t: &TrackLocalContext) -> webrtc::error::Result<()> {
+        self.bound.send_replace(false);
+        self.rtp.unbind(t).await
+    }
+
+    fn id(&self) -> &str {
+        self.rtp.id()
+    }
+
+    fn rid(&self) -> Option<&str> {
+        self.rtp.rid()
+    }
+
+    fn stream_id(&self) -> &str {
+        self.rtp.stream_id()
+    }
+
+    fn kind(&self) -> RTPCodecType {
+        self.rtp.kind()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
 }
 
 /// MediaTrackSubscriber is created from a MediaTrackRouter and contains a new TrackLocalStaticRTP
 /// that can be added to another Peer's subscriber RTCPeerConnection)
 pub struct MediaTrackSubscriber {
-    track: Arc<TrackLocalStaticRTP>,
-    pkt_receiver: broadcast::Receiver<rtp::packet::Packet>,
+    track: Arc<BindingTrack>,
+    bound: watch::Receiver<bool>,
+    mime_type: String,
+    munger: RtpMunger,
+    pkt_receiver: broadcast::Receiver<LayerPacket>,
     evt_sender: mpsc::Sender<MediaTrackSubscriberEvent>,
+
+    // NACKed (outgoing) sequence numbers from the RTCP loop, answered from the router's caches
+    nack_sender: mpsc::Sender<Vec<u16>>,
+    nack_receiver: mpsc::Receiver<Vec<u16>>,
+    caches: Arc<Mutex<HashMap<u32, PacketCacheHandle>>>,
+    counters: Arc<RouterCounters>,
+
+    // Layer currently being forwarded, shared with the RTCP loop so PLIs reach the right ssrc
+    current_layer: Arc<Mutex<Option<Arc<str>>>>,
+    handle: MediaTrackSubscriberHandle,
+    target_layer: watch::Receiver<Option<String>>,
 }
 
 impl MediaTrackSubscriber {
     pub(super) async fn new(
         remote: &TrackRemote,
-        pkt_receiver: broadcast::Receiver<rtp::packet::Packet>,
+        id: String,
+        stream_id: String,
+        pkt_receiver: broadcast::Receiver<LayerPacket>,
         evt_sender: mpsc::Sender<MediaTrackSubscriberEvent>,
+        caches: Arc<Mutex<HashMap<u32, PacketCacheHandle>>>,
+        counters: Arc<RouterCounters>,
     ) -> MediaTrackSubscriber {
-        let output_track = Arc::new(TrackLocalStaticRTP::new(
-            remote.codec().capability,
-            remote.id(),
-            remote.stream_id(),
-        ));
+        let codec = remote.codec().capability;
+        let mime_type = codec.mime_type.clone();
+        let munger = RtpMunger::new(codec.clock_rate);
+        let (bound_tx, bound_rx) = watch::channel(false);
+        let output_track = Arc::new(BindingTrack {
+            rtp: TrackLocalStaticRTP::new(codec, id, stream_id),
+            bound: bound_tx,
+        });
 
         debug!(
             "MediaTrackSubscriber created track={} stream={}",
             output_track.id(),
             output_track.stream_id()
         );
+
+        let (layer_tx, layer_rx) = watch::channel(None);
+        let (nack_tx, nack_rx) = mpsc::channel(32);
         MediaTrackSubscriber {
             track: output_track,
+            bound: bound_rx,
+            mime_type,
+            munger,
             pkt_receiver,
             evt_sender,
+            nack_sender: nack_tx,
+            nack_receiver: nack_rx,
+            caches,
+            counters,
+            current_layer: Arc::new(Mutex::new(None)),
+            handle: MediaTrackSubscriberHandle {
+                target_layer: Arc::new(layer_tx),
+                stop: Arc::new(Notify::new()),
+            },
+            target_layer: layer_rx,
         }
     }
 
+    /// Id of the outgoing track (matches the id of the MediaTrackRouter)
+    pub fn id(&self) -> String {
+        self.track.id().to_owned()
+    }
+
+    pub fn handle(&self) -> MediaTrackSubscriberHandle {
+        self.handle.clone()
+    }
+
     pub async fn add_to_peer_connection(
         &self,
         peer_connection: &RTCPeerConnection,
@@ -59,16 +183,20 @@ impl MediaTrackSubscriber {
         // Add this newly created track to the PeerConnection
         let rtp_sender = peer_connection
             .add_track(Arc::clone(&self.track) as Arc<dyn TrackLocal + Send + Sync>)
-            .await?;
+            .await
+            .map_err(Error::PeerConnection)?;
 
         let evt_sender = self.evt_sender.clone();
+        let nack_sender = self.nack_sender.clone();
+        let current_layer = self.current_layer.clone();
+        let counters = self.counters.clone();
 
         // Read incoming RTCP packets
         // Before these packets are returned they are processed by interceptors. For things
         // like NACK this needs to be called
-        tokio::spawn(
-            enc!((rtp_sender) async move { MediaTrackSubscriber::rtcp_event_loop(rtp_sender, evt_sender).await }),
-        );
+        tokio::spawn(enc!((rtp_sender) async move {
+            MediaTrackSubscriber::rtcp_event_loop(rtp_sender, evt_sender, nack_sender, current_layer, counters).await
+        }));
 
         Ok(rtp_sender)
     }
@@ -82,15 +210,88 @@ impl MediaTrackSubscriber {
 
         // Asynchronously take all packets in the channel and write them out to our
         // track
-        let mut curr_timestamp = 0;
-        let mut i = 0;
+        let mut current: Option<Arc<str>> = None;
 
-        while let Ok(mut packet) = self.pkt_receiver.recv().await {
-            // Timestamp on the packet is really a diff, so add it to current
-            curr_timestamp += packet.header.timestamp;
-            packet.header.timestamp = curr_timestamp;
-            // Keep an increasing sequence number
-            packet.header.sequence_number = i;
+        // Nothing is forwarded until a keyframe arrives, so ask for one as soon as the track is
+        // bound rather than waiting for the publisher's next natural keyframe
+        if *self.bound.borrow_and_update() {
+            let target = self.target_layer.borrow().clone();
+            self.request_keyframe(target);
+        }
+
+        let stop = self.handle.stop.clone();
+        loop {
+            let LayerPacket { rid, mut packet } = tokio::select! {
+                _ = stop.notified() => break,
+                res = self.bound.changed() => {
+                    if res.is_err() {
+                        break;
+                    }
+                    if *self.bound.borrow_and_update() && current.is_none() {
+                        debug!("MediaTrackSubscriber track bound");
+                        let target = self.target_layer.borrow().clone();
+                        self.request_keyframe(target);
+                    }
+                    continue;
+                }
+                res = self.pkt_receiver.recv() => match res {
+                    Ok(p) => p,
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        warn!("MediaTrackSubscriber lagged, skipped {} packets", n);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                res = self.target_layer.changed() => {
+                    if res.is_err() {
+                        break;
+                    }
+                    let target = self.target_layer.borrow_and_update().clone();
+                    if let Some(target) = target {
+                        if current.as_deref() != Some(target.as_str()) {
+                            debug!("MediaTrackSubscriber switching to layer={}", target);
+                            self.request_keyframe(Some(target));
+                        }
+                    }
+                    continue;
+                }
+                Some(sequence_numbers) = self.nack_receiver.next() => {
+                    self.retransmit(sequence_numbers).await;
+                    continue;
+                }
+            };
+
+            // Unbound (not negotiated yet) the packets would go nowhere
+            if !*self.bound.borrow() {
+                continue;
+            }
+
+            // Simulcast layers share one outgoing track, only forward the selected one
+            let target = if rid.is_empty() {
+                None
+            } else {
+                self.target_layer.borrow().clone()
+            };
+            if !self.should_forward(&current, target.clone(), &rid, &packet) {
+                continue;
+            }
+
+            let starting = current.is_none();
+            if current.as_ref() != Some(&rid) {
+                debug!("MediaTrackSubscriber forwarding layer={}", rid);
+                current = Some(rid.clone());
+                *self.current_layer.lock().await = current.clone();
+            }
+
+            // Rewrite sequence/timestamp so layer switches look like one continuous stream
+            if !self.munger.process(&mut packet.header, Instant::now()) {
+                trace!(
+                    "MediaTrackSubscriber dropped RTP ssrc={} seq={}",
+                    packet.header.ssrc,
+                    packet.header.sequence_number
+                );
+                continue;
+            }
 
             trace!(
                 "MediaTrackSubscriber wrote RTP ssrc={} seq={} timestamp={}",
@@ -100,16 +301,25 @@ impl MediaTrackSubscriber {
             );
 
             // Write out the packet, ignoring closed pipe if nobody is listening
-            if let Err(err) = self.track.write_rtp(&packet).await {
-                if Error::ErrClosedPipe == err {
-                    // The peerConnection has been closed.
-                    debug!("MediaTrackSubscriber write_rtp ErrClosedPipe");
-                    break;
-                } else {
-                    error!("MediaTrackSubscriber failed {}", err);
+            match self.track.rtp.write_rtp(&packet).await {
+                Ok(0) if starting => {
+                    // The binding went away so the keyframe went nowhere, keep waiting for
+                    // another one
+                    trace!("MediaTrackSubscriber track not bound");
+                    current = None;
+                    *self.current_layer.lock().await = None;
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    if webrtc::Error::ErrClosedPipe == err {
+                        // The peerConnection has been closed.
+                        debug!("MediaTrackSubscriber write_rtp ErrClosedPipe");
+                        break;
+                    } else {
+                        error!("MediaTrackSubscriber failed {}", err);
+                    }
                 }
             }
-            i += 1;
         }
 
         debug!(
@@ -119,11 +329,90 @@ impl MediaTrackSubscriber {
         );
     }
 
+    /// Decides if a packet is forwarded
+    /// Forwarding only starts, or switches to the target simulcast layer, on a keyframe so the
+    /// decoder never sees a partial picture
+    fn should_forward(
+        &self,
+        current: &Option<Arc<str>>,
+        target: Option<String>,
+        rid: &str,
+        packet: &rtp::packet::Packet,
+    ) -> bool {
+        let is_keyframe =
+            || keyframe::is_keyframe(&self.mime_type, &packet.payload).unwrap_or(true);
+
+        match (current, target) {
+            (Some(current), _) if current.as_ref() == rid => true,
+            (_, Some(target)) => target == rid && is_keyframe(),
+            (None, None) => is_keyframe(),
+            (Some(_), None) => false,
+        }
+    }
+
+    /// Answers NACKed sequence numbers from the router's packet cache
+    /// Packets missing from the cache are requested from the publisher instead
+    async fn retransmit(&mut self, sequence_numbers: Vec<u16>) {
+        let mut missing: HashMap<u32, Vec<u16>> = HashMap::new();
+
+        for seq in sequence_numbers {
+            let (ssrc, source_seq, ts_offset) = match self.munger.lookup(seq) {
+                Some(source) => source,
+                None => continue,
+            };
+
+            let cache = self.caches.lock().await.get(&ssrc).cloned();
+            let cached = match cache {
+                Some(cache) => cache.lock().await.get(source_seq),
+                None => None,
+            };
+
+            match cached {
+                Some(mut packet) => {
+                    packet.header.sequence_number = seq;
+                    packet.header.timestamp = packet.header.timestamp.wrapping_add(ts_offset);
+
+                    trace!("MediaTrackSubscriber retransmitting seq={}", seq);
+                    match self.track.rtp.write_rtp(&packet).await {
+                        Ok(_) => {
+                            self.counters
+                                .retransmitted_packets
+                                .fetch_add(1, Ordering::Relaxed);
+                        }
+                        Err(err) => debug!("MediaTrackSubscriber retransmit failed {}", err),
+                    }
+                }
+                None => missing.entry(ssrc).or_default().push(source_seq),
+            }
+        }
+
+        for (ssrc, sequence_numbers) in missing {
+            if let Err(err) = self.evt_sender.try_send(MediaTrackSubscriberEvent::Nack {
+                ssrc,
+                sequence_numbers,
+            }) {
+                debug!("MediaTrackSubscriber couldn't forward NACK: {}", err);
+            }
+        }
+    }
+
+    fn request_keyframe(&mut self, rid: Option<String>) {
+        if let Err(err) = self
+            .evt_sender
+            .try_send(MediaTrackSubscriberEvent::KeyframeRequest { rid })
+        {
+            debug!("MediaTrackSubscriber couldn't request keyframe: {}", err);
+        }
+    }
+
     async fn rtcp_event_loop(
         rtp_sender: Arc<RTCRtpSender>,
         mut evt_sender: mpsc::Sender<MediaTrackSubscriberEvent>,
+        mut nack_sender: mpsc::Sender<Vec<u16>>,
+        current_layer: Arc<Mutex<Option<Arc<str>>>>,
+        counters: Arc<RouterCounters>,
     ) {
-        use rtcp::header::{PacketType, FORMAT_PLI};
+        use rtcp::header::{PacketType, FORMAT_FIR, FORMAT_PLI, FORMAT_TLN};
 
         debug!("MediaTrackSubscriber RTCP ReadLoop starting");
 
@@ -137,24 +426,41 @@ impl MediaTrackSubscriber {
 
                 let header = rtcp.header();
                 match header.packet_type {
-                    PacketType::ReceiverReport => {
-                        let _rr = &rtcp
-                            .as_any()
-                            .downcast_ref::<rtcp::receiver_report::ReceiverReport>()
-                            .unwrap();
+                    // PLI and FIR both ask for a keyframe of the layer being forwarded
+                    PacketType::PayloadSpecificFeedback
+                        if header.count == FORMAT_PLI || header.count == FORMAT_FIR =>
+                    {
+                        let rid = current_layer
+                            .lock()
+                            .await
+                            .as_ref()
+                            .map(|rid| rid.to_string());
+                        if let Err(err) =
+                            evt_sender.try_send(MediaTrackSubscriberEvent::KeyframeRequest { rid })
+                        {
+                            debug!("MediaTrackSubscriber dropped keyframe request: {}", err);
+                        }
                     }
-                    PacketType::PayloadSpecificFeedback => match header.count {
-                        FORMAT_PLI => {
-                            let _pli = &rtcp
-                                    .as_any()
-                                    .downcast_ref::<rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication>()
-                                    .unwrap();
-                            evt_sender
-                                .try_send(MediaTrackSubscriberEvent::PictureLossIndication)
-                                .unwrap();
+                    PacketType::TransportSpecificFeedback if header.count == FORMAT_TLN => {
+                        let nack = match rtcp
+                                .as_any()
+                                .downcast_ref::<rtcp::transport_feedbacks::transport_layer_nack::TransportLayerNack>() {
+                            Some(nack) => nack,
+                            None => continue,
+                        };
+                        let sequence_numbers: Vec<u16> = nack
+                            .nacks
+                            .iter()
+                            .flat_map(|pair| pair.packet_list())
+                            .collect();
+
+                        counters
+                            .nacks_received
+                            .fetch_add(sequence_numbers.len() as u64, Ordering::Relaxed);
+                        if let Err(err) = nack_sender.try_send(sequence_numbers) {
+                            debug!("MediaTrackSubscriber dropped NACK: {}", err);
                         }
-                        _ => {}
-                    },
+                    }
                     _ => {}
                 }
             }
@@ -1,4 +1,3 @@
-use anyhow::{format_err, Result};
 use async_mutex::Mutex;
 use async_trait::async_trait;
 use enclose::enc;
@@ -10,23 +9,32 @@ use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
 use crate::sfu::peer;
-use crate::sfu::routing::MediaTrackRouterHandle;
+use crate::sfu::routing::{MediaTrackRouter, MediaTrackRouterHandle, MediaTrackRouterStats};
 use crate::signal::signal;
+use crate::{Error, Result};
 
 // SessionID represents a collection of peers that can route tracks to eachother
 pub type Id = String;
 pub type SessionHandle<T> = Arc<T>;
 
+/// Limits of a single session
+#[derive(Clone, Copy, Default, Debug)]
+pub struct SessionConfig {
+    /// Peers allowed in the session at once, unlimited if None
+    pub max_peers: Option<usize>,
+}
+
 pub type ReadStream = mpsc::Receiver<SessionEvent>;
 pub type WriteStream = mpsc::Sender<SessionEvent>;
 
 /// Session
 /// Session is a single logical call within switchboard
-/// All published tracks are routed to all subscribers
+/// Published tracks are routed to every other peer whose subscriptions match them (and back to
+/// the publisher with loopback)
 #[async_trait]
 pub trait Session {
     /// Create a new session
-    fn new(id: Id) -> SessionHandle<Self>;
+    fn new(id: Id, cfg: SessionConfig) -> SessionHandle<Self>;
     /// Session ID
     fn id(&self) -> Id;
     /// Returns true if there are connected peers within this session
@@ -42,12 +50,41 @@ pub trait Session {
     /// Sets the metadata json for a given peer::Id
     /// (this is for application specific json to be broadcasted to all connected peers)
     async fn presence_set(&self, id: peer::Id, meta: serde_json::Value);
+
+    /// Returns every peer in this session with the identity it joined with
+    async fn participants(&self) -> Vec<signal::Participant>;
+
+    /// Returns routing stats for every track published in this session
+    async fn stats(&self) -> Vec<MediaTrackRouterStats>;
+
+    /// Subscribes a peer to tracks by id and/or kind, renegotiating once
+    /// Returns the ids of every track the peer is now subscribed to
+    async fn subscribe(
+        &self,
+        id: peer::Id,
+        track_ids: Vec<String>,
+        kinds: Vec<String>,
+    ) -> Result<Vec<String>>;
+    /// Unsubscribes a peer from tracks by id and/or kind, renegotiating once
+    /// Returns the ids of every track the peer is still subscribed to
+    async fn unsubscribe(
+        &self,
+        id: peer::Id,
+        track_ids: Vec<String>,
+        kinds: Vec<String>,
+    ) -> Result<Vec<String>>;
+
+    /// Changes a peer's role, then renegotiates its subscriptions and tells every peer
+    /// Returns the participant with its new role
+    async fn set_role(&self, id: peer::Id, role: peer::Role) -> Result<signal::Participant>;
 }
 
 /// SessionEvent allows sfu::Peer to publish changes to the session and have the session react
 pub enum SessionEvent {
     TrackPublished(MediaTrackRouterHandle),
-    TrackRemoved(String),
+    /// A simulcast layer was added to an already published track
+    TrackUpdated(String),
+    TrackRemoved(MediaTrackRouterHandle),
 }
 
 /// LocalSession
@@ -55,6 +92,7 @@ pub enum SessionEvent {
 /// route traffic between sfu::Peer's
 pub struct LocalSession {
     pub id: Id,
+    cfg: SessionConfig,
     peers: Arc<Mutex<HashMap<peer::Id, Arc<peer::Peer>>>>,
     routers: Arc<Mutex<HashMap<String, MediaTrackRouterHandle>>>,
     tx: WriteStream,
@@ -65,11 +103,12 @@ pub struct LocalSession {
 
 #[async_trait]
 impl Session for LocalSession {
-    fn new(id: Id) -> SessionHandle<LocalSession> {
+    fn new(id: Id, cfg: SessionConfig) -> SessionHandle<LocalSession> {
         let (tx, rx) = mpsc::channel(16);
 
         let handle = Arc::new(LocalSession {
             id,
+            cfg,
             peers: Arc::new(Mutex::new(HashMap::new())),
             routers: Arc::new(Mutex::new(HashMap::new())),
             tx,
@@ -90,7 +129,7 @@ impl Session for LocalSession {
 
     async fn active(&self) -> bool {
         let peers = self.peers.lock().await;
-        peers.len() > 0
+        !peers.is_empty()
     }
 
     fn write_channel(&self) -> WriteStream {
@@ -102,10 +141,26 @@ impl Session for LocalSession {
 
         if peers.contains_key(&id) {
             error!("Peer id={} already exists", id);
-            return Err(format_err!("Peer id={} already exists", id));
+            return Err(Error::PeerExists(id));
+        }
+        if self.cfg.max_peers.is_some_and(|max| peers.len() >= max) {
+            return Err(Error::CapacityExceeded(format!(
+                "session {} is full",
+                self.id
+            )));
         }
 
-        self.subscribe_peer_to_all_routers(&peer).await;
+        let joined = LocalSession::participant(&peer).await;
+        for other in peers.values() {
+            other
+                .signal_tx
+                .send(signal::Event::PeerJoined(joined.clone()));
+        }
+
+        // Still holding the peers: add_router can't be between adding a router and
+        // subscribing the peers, so every router is announced and subscribed exactly once
+        self.announce_routers(&peer).await;
+        self.update_subscriptions(&peer).await;
         peers.insert(id, peer);
 
         debug!("LocalSession(id={}) Added Peer(id={})", self.id, id);
@@ -116,34 +171,93 @@ impl Session for LocalSession {
     async fn remove_peer(&self, id: peer::Id) -> Result<()> {
         let mut peers = self.peers.lock().await;
 
-        if let Some(peer) = peers.remove(&id) {
-            debug!("LocalSession(id={}) Removed Peer(id={})", self.id, id);
-            peer.close().await;
+        let peer = match peers.remove(&id) {
+            Some(peer) => peer,
+            None => return Ok(()),
+        };
+        drop(peers);
+
+        debug!("LocalSession(id={}) Removed Peer(id={})", self.id, id);
+        let closed = peer.close().await;
+
+        self.broadcast(|| signal::Event::PeerLeft(signal::PeerLeftNotification { peer_id: id }))
+            .await;
+
+        let mut presence = self.presence_meta.lock().await;
+        if presence.remove(&id).is_some() {
+            self.broadcast_presence(&presence).await;
         }
 
-        Ok(())
+        // the peer is gone from the session either way
+        closed
     }
 
     async fn presence_set(&self, id: peer::Id, meta: serde_json::Value) {
         let mut presence = self.presence_meta.lock().await;
         presence.insert(id, meta);
+        self.broadcast_presence(&presence).await;
+    }
 
-        let mut rev: u64 = self.presence_revision.load(Ordering::SeqCst);
-        rev += 1;
-        self.presence_revision.store(rev, Ordering::SeqCst);
+    async fn participants(&self) -> Vec<signal::Participant> {
+        let peers = self.peers.lock().await;
 
-        let p = signal::Presence {
-            revision: rev,
-            meta: serde_json::to_value(&*presence).unwrap(),
-        };
+        let mut participants = vec![];
+        for peer in peers.values() {
+            participants.push(LocalSession::participant(peer).await);
+        }
+        participants
+    }
 
-        let peers = self.peers.lock().await;
+    async fn stats(&self) -> Vec<MediaTrackRouterStats> {
+        let routers = self.routers.lock().await;
 
-        for (_, peer) in &*peers {
-            peer.signal_tx
-                .unbounded_send(Ok(signal::Event::Presence(p.clone())))
-                .ok();
+        let mut stats = vec![];
+        for router in routers.values() {
+            stats.push(router.lock().await.stats().await);
         }
+        stats
+    }
+
+    async fn subscribe(
+        &self,
+        id: peer::Id,
+        track_ids: Vec<String>,
+        kinds: Vec<String>,
+    ) -> Result<Vec<String>> {
+        let peer = self.get_peer(id).await?;
+        peer.subscribe(&track_ids, &kinds).await;
+        self.update_subscriptions(&peer).await;
+        Ok(peer.subscribed_track_ids().await)
+    }
+
+    async fn unsubscribe(
+        &self,
+        id: peer::Id,
+        track_ids: Vec<String>,
+        kinds: Vec<String>,
+    ) -> Result<Vec<String>> {
+        let peer = self.get_peer(id).await?;
+        peer.unsubscribe(&track_ids, &kinds).await;
+        self.update_subscriptions(&peer).await;
+        Ok(peer.subscribed_track_ids().await)
+    }
+
+    async fn set_role(&self, id: peer::Id, role: peer::Role) -> Result<signal::Participant> {
+        let peer = self.get_peer(id).await?;
+
+        // a new subscriber connection and its tracks are negotiated with a single offer
+        peer.begin_negotiation_batch().await;
+        let result = peer.set_role(role).await;
+        if result.is_ok() {
+            self.update_subscriptions(&peer).await;
+        }
+        peer.end_negotiation_batch().await;
+        result?;
+
+        let participant = LocalSession::participant(&peer).await;
+        self.broadcast(|| signal::Event::RoleChanged(participant.clone()))
+            .await;
+        Ok(participant)
     }
 }
 
@@ -152,40 +266,175 @@ impl LocalSession {
         while let Some(evt) = rx.next().await {
             match evt {
                 SessionEvent::TrackPublished(router) => session.add_router(router).await,
-                SessionEvent::TrackRemoved(router_id) => session.remove_router(router_id).await,
+                SessionEvent::TrackUpdated(router_id) => session.update_router(router_id).await,
+                SessionEvent::TrackRemoved(router) => session.remove_router(router).await,
             }
         }
     }
 
     async fn add_router(&self, router: MediaTrackRouterHandle) {
-        self.subscribe_all_peers_to_router(&router).await;
+        let info = { LocalSession::track_info(&*router.lock().await).await };
+
+        // The peers stay locked while the router is added, so a joining peer either finds it
+        // in routers or is subscribed to it here
+        let peers = self.peers.lock().await;
+        self.routers
+            .lock()
+            .await
+            .insert(info.track_id.clone(), router.clone());
+        LocalSession::subscribe_peers_to_router(&peers, &router).await;
+
+        for peer in peers.values() {
+            peer.signal_tx
+                .send(signal::Event::TrackPublished(info.clone()));
+        }
+    }
 
-        let id = { router.lock().await.id.clone() };
-        let mut routers = self.routers.lock().await;
-        routers.insert(id, router);
+    async fn update_router(&self, router_id: String) {
+        let router = self.routers.lock().await.get(&router_id).cloned();
+        if let Some(router) = router {
+            let info = { LocalSession::track_info(&*router.lock().await).await };
+            self.broadcast(|| signal::Event::TrackPublished(info.clone()))
+                .await;
+        }
     }
 
-    async fn remove_router(&self, router_id: String) {
-        let _ = self.peers.lock().await;
-        let mut routers = self.routers.lock().await;
-        let _router = routers.remove(&router_id);
+    async fn remove_router(&self, router: MediaTrackRouterHandle) {
+        let (router_id, publisher) = {
+            let router = router.lock().await;
+            (router.id.clone(), router.publisher)
+        };
+
+        // Only remove the exact router, a newer one may have been published with the same id
+        let removed = {
+            let mut routers = self.routers.lock().await;
+            match routers.get(&router_id) {
+                Some(r) if Arc::ptr_eq(r, &router) => routers.remove(&router_id).is_some(),
+                _ => false,
+            }
+        };
+
+        if removed {
+            let notification = signal::TrackUnpublishedNotification {
+                peer_id: publisher,
+                track_id: router_id,
+            };
+            self.broadcast(|| signal::Event::TrackUnpublished(notification.clone()))
+                .await;
+        }
     }
 
-    async fn subscribe_all_peers_to_router(&self, router: &MediaTrackRouterHandle) {
-        let mut peers = self.peers.lock().await;
+    /// Bumps the presence revision and sends the whole presence map to every peer
+    async fn broadcast_presence(&self, presence: &HashMap<peer::Id, serde_json::Value>) {
+        let rev = self.presence_revision.fetch_add(1, Ordering::SeqCst) + 1;
 
-        for (_, peer) in &mut *peers {
-            let subscriber = { router.lock().await.add_subscriber().await };
-            peer.add_media_track_subscriber(subscriber).await;
+        let p = signal::Presence {
+            revision: rev,
+            meta: serde_json::to_value(presence).unwrap(),
+        };
+        self.broadcast(|| signal::Event::Presence(p.clone())).await;
+    }
+
+    async fn participant(peer: &peer::Peer) -> signal::Participant {
+        signal::Participant {
+            peer_id: peer.id,
+            identity: peer.identity.clone(),
+            role: peer.role().await,
         }
     }
 
-    async fn subscribe_peer_to_all_routers(&self, peer: &Arc<peer::Peer>) {
-        let mut routers = self.routers.lock().await;
+    /// Sends a signal::Event to every peer in the session
+    async fn broadcast<F: Fn() -> signal::Event>(&self, evt: F) {
+        let peers = self.peers.lock().await;
+        for peer in peers.values() {
+            peer.signal_tx.send(evt());
+        }
+    }
 
-        for (_, router) in &mut *routers {
-            let subscriber = { router.lock().await.add_subscriber().await };
-            peer.add_media_track_subscriber(subscriber).await;
+    /// Tells a newly added peer about the tracks already published
+    async fn announce_routers(&self, peer: &peer::Peer) {
+        let routers = self.routers.lock().await;
+        for router in routers.values() {
+            let info = LocalSession::track_info(&*router.lock().await).await;
+            peer.signal_tx.send(signal::Event::TrackPublished(info));
         }
     }
+
+    async fn track_info(router: &MediaTrackRouter) -> signal::TrackInfo {
+        let mut layers = router.layers().await;
+        layers.retain(|rid| !rid.is_empty());
+        layers.sort();
+
+        signal::TrackInfo {
+            peer_id: router.publisher,
+            track_id: router.id.clone(),
+            stream_id: router.stream_id(),
+            publisher_track_id: router.publisher_track_id(),
+            publisher_stream_id: router.publisher_stream_id(),
+            kind: router.kind(),
+            codec: router.codec(),
+            layers,
+            labels: router.labels.clone(),
+        }
+    }
+
+    async fn get_peer(&self, id: peer::Id) -> Result<Arc<peer::Peer>> {
+        match self.peers.lock().await.get(&id) {
+            Some(peer) => Ok(peer.clone()),
+            None => Err(Error::PeerNotFound(id)),
+        }
+    }
+
+    async fn subscribe_peers_to_router(
+        peers: &HashMap<peer::Id, Arc<peer::Peer>>,
+        router: &MediaTrackRouterHandle,
+    ) {
+        let router = router.lock().await;
+
+        for peer in peers.values() {
+            if !LocalSession::should_route(peer, &router).await {
+                continue;
+            }
+            let subscriber = router.add_subscriber().await;
+            if let Err(err) = peer.add_media_track_subscriber(subscriber).await {
+                error!(
+                    "error subscribing peer id={} to track id={}: {}",
+                    peer.id, router.id, err
+                );
+            }
+        }
+    }
+
+    /// Attaches and detaches MediaTrackSubscribers so the peer receives exactly the tracks it
+    /// should, with a single renegotiation for all changes
+    async fn update_subscriptions(&self, peer: &Arc<peer::Peer>) {
+        let routers = self.routers.lock().await;
+
+        peer.begin_negotiation_batch().await;
+        for router in routers.values() {
+            let router = router.lock().await;
+            let wanted = LocalSession::should_route(peer, &router).await;
+            let subscribed = peer.is_subscribed(&router.id).await;
+
+            if wanted && !subscribed {
+                let subscriber = router.add_subscriber().await;
+                if let Err(err) = peer.add_media_track_subscriber(subscriber).await {
+                    error!("error subscribing track id={}: {}", router.id, err);
+                }
+            } else if !wanted && subscribed {
+                if let Err(err) = peer.remove_media_track_subscriber(&router.id).await {
+                    error!("error unsubscribing track id={}: {}", router.id, err);
+                }
+            }
+        }
+        peer.end_negotiation_batch().await;
+    }
+
+    /// Peers only get tracks matching their subscriptions if their role subscribes, and their
+    /// own tracks back only when they joined with loopback
+    async fn should_route(peer: &peer::Peer, router: &MediaTrackRouter) -> bool {
+        (peer.id != router.publisher || peer.loopback)
+            && peer.role().await.subscribes()
+            && peer.wants_track(&router.id, &router.kind()).await
+    }
 }
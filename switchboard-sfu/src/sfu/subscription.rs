@@ -0,0 +1,123 @@
+use std::collections::HashSet;
+
+/// SubscriptionFilter decides which published tracks a Peer receives
+/// Tracks can be picked by kind ("audio"/"video") or by id, explicitly unsubscribing a track id
+/// wins over its kind
+#[derive(Default, Debug)]
+pub struct SubscriptionFilter {
+    kinds: HashSet<String>,
+    track_ids: HashSet<String>,
+    excluded_track_ids: HashSet<String>,
+}
+
+impl SubscriptionFilter {
+    /// Filter matching every track (auto subscribe)
+    pub fn all() -> SubscriptionFilter {
+        SubscriptionFilter {
+            kinds: ["audio", "video"].iter().map(|k| k.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    pub fn wants(&self, track_id: &str, kind: &str) -> bool {
+        if self.excluded_track_ids.contains(track_id) {
+            return false;
+        }
+        self.track_ids.contains(track_id) || self.kinds.contains(kind)
+    }
+
+    pub fn subscribe(&mut self, track_ids: &[String], kinds: &[String]) {
+        for id in track_ids {
+            self.excluded_track_ids.remove(id);
+            self.track_ids.insert(id.clone());
+        }
+        self.kinds.extend(kinds.iter().cloned());
+    }
+
+    pub fn unsubscribe(&mut self, track_ids: &[String], kinds: &[String]) {
+        for id in track_ids {
+            self.track_ids.remove(id);
+            self.excluded_track_ids.insert(id.clone());
+        }
+        for kind in kinds {
+            self.kinds.remove(kind);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn matches_nothing_by_default() {
+        let filter = SubscriptionFilter::default();
+        assert!(!filter.wants("a", "audio"));
+        assert!(!filter.wants("v", "video"));
+    }
+
+    #[test]
+    fn all_matches_every_kind() {
+        let filter = SubscriptionFilter::all();
+        assert!(filter.wants("a", "audio"));
+        assert!(filter.wants("v", "video"));
+    }
+
+    #[test]
+    fn matches_kinds() {
+        let mut filter = SubscriptionFilter::default();
+        filter.subscribe(&[], &strings(&["audio"]));
+        assert!(filter.wants("a", "audio"));
+        assert!(!filter.wants("v", "video"));
+
+        filter.unsubscribe(&[], &strings(&["audio"]));
+        assert!(!filter.wants("a", "audio"));
+    }
+
+    #[test]
+    fn matches_track_ids() {
+        let mut filter = SubscriptionFilter::default();
+        filter.subscribe(&strings(&["v1"]), &[]);
+        assert!(filter.wants("v1", "video"));
+        assert!(!filter.wants("v2", "video"));
+
+        filter.unsubscribe(&strings(&["v1"]), &[]);
+        assert!(!filter.wants("v1", "video"));
+    }
+
+    #[test]
+    fn track_ids_add_to_kinds() {
+        let mut filter = SubscriptionFilter::default();
+        filter.subscribe(&strings(&["v1"]), &strings(&["audio"]));
+        assert!(filter.wants("a", "audio"));
+        assert!(filter.wants("v1", "video"));
+        assert!(!filter.wants("v2", "video"));
+    }
+
+    #[test]
+    fn exclusion_wins_over_kind() {
+        let mut filter = SubscriptionFilter::all();
+        filter.unsubscribe(&strings(&["v1"]), &[]);
+        assert!(!filter.wants("v1", "video"));
+        assert!(filter.wants("v2", "video"));
+
+        // subscribing the kind again keeps the track excluded
+        filter.subscribe(&[], &strings(&["video"]));
+        assert!(!filter.wants("v1", "video"));
+    }
+
+    #[test]
+    fn subscribing_a_track_id_lifts_its_exclusion() {
+        let mut filter = SubscriptionFilter::all();
+        filter.unsubscribe(&strings(&["v1"]), &strings(&["video"]));
+        assert!(!filter.wants("v1", "video"));
+
+        filter.subscribe(&strings(&["v1"]), &[]);
+        assert!(filter.wants("v1", "video"));
+        assert!(!filter.wants("v2", "video"));
+    }
+}
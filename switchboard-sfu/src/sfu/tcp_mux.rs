@@ -0,0 +1,420 @@
+use async_mutex::Mutex;
+use async_trait::async_trait;
+use enclose::enc;
+use log::*;
+use std::any::Any;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use webrtc::ice::candidate::candidate_base::{unmarshal_candidate, CandidateBaseConfig};
+use webrtc::ice::candidate::candidate_host::CandidateHostConfig;
+use webrtc::ice::candidate::{Candidate, CandidateType};
+use webrtc::ice::tcp_type::TcpType;
+use webrtc::ice::udp_mux::{UDPMux, UDPMuxConn, UDPMuxDefault};
+use webrtc::stun::attributes::ATTR_USERNAME;
+use webrtc::stun::message::{Message, BINDING_REQUEST};
+use webrtc::stun::textattrs::TextAttribute;
+use webrtc::util::Conn;
+
+/// How long a new stream has to send its first binding request
+const FIRST_FRAME_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// UDP mux that also takes ICE-TCP (RFC 6544) streams on a listener, for clients that can't
+/// use UDP
+/// webrtc-ice only gathers UDP candidates, so every UDP host candidate is signaled with a
+/// passive TCP twin on the listener's port (see tcp_candidate). A stream belongs to the ICE
+/// agent named by the USERNAME of its first binding request, its RFC 4571 frames reach the
+/// agent as if they came over UDP from the stream's address and what the agent sends to that
+/// address goes back on the stream.
+pub struct TcpMux {
+    udp: Arc<UDPMuxDefault>,
+    // connections by local ufrag
+    conns: Arc<Mutex<HashMap<String, Arc<TcpMuxConn>>>>,
+    accept_loop: JoinHandle<()>,
+}
+
+impl TcpMux {
+    pub fn new(udp: Arc<UDPMuxDefault>, listener: TcpListener) -> Arc<TcpMux> {
+        let conns: Arc<Mutex<HashMap<String, Arc<TcpMuxConn>>>> =
+            Arc::new(Mutex::new(HashMap::new()));
+
+        let accept_loop = tokio::spawn(enc!( (conns) async move {
+            while let Ok((stream, addr)) = listener.accept().await {
+                debug!("ice tcp connection from {}", addr);
+                tokio::spawn(enc!( (conns) async move {
+                    if let Err(err) = accept_stream(stream, addr, &conns).await {
+                        debug!("ice tcp connection from {} failed: {}", addr, err);
+                    }
+                }));
+            }
+        }));
+
+        Arc::new(TcpMux {
+            udp,
+            conns,
+            accept_loop,
+        })
+    }
+}
+
+impl Drop for TcpMux {
+    fn drop(&mut self) {
+        self.accept_loop.abort();
+    }
+}
+
+#[async_trait]
+impl UDPMux for TcpMux {
+    async fn close(&self) -> Result<(), webrtc::util::Error> {
+        self.accept_loop.abort();
+        for (_, conn) in self.conns.lock().await.drain() {
+            conn.close_streams().await;
+        }
+        self.udp.close().await
+    }
+
+    async fn get_conn(
+        self: Arc<Self>,
+        ufrag: &str,
+    ) -> Result<Arc<dyn Conn + Send + Sync>, webrtc::util::Error> {
+        let mut conns = self.conns.lock().await;
+        if let Some(conn) = conns.get(ufrag) {
+            if !conn.udp.is_closed() {
+                return Ok(conn.clone());
+            }
+        }
+
+        let udp = self.udp.clone().get_conn(ufrag).await?;
+        let udp = udp
+            .as_any()
+            .downcast_ref::<UDPMuxConn>()
+            .cloned()
+            .ok_or_else(|| webrtc::util::Error::Other("not a udp mux connection".to_owned()))?;
+        let conn = Arc::new(TcpMuxConn {
+            udp,
+            streams: Mutex::new(HashMap::new()),
+        });
+        conns.insert(ufrag.to_owned(), conn.clone());
+        Ok(conn)
+    }
+
+    async fn remove_conn_by_ufrag(&self, ufrag: &str) {
+        if let Some(conn) = self.conns.lock().await.remove(ufrag) {
+            conn.close_streams().await;
+        }
+        self.udp.remove_conn_by_ufrag(ufrag).await;
+    }
+}
+
+/// Connection of one ICE agent, UDP traffic goes through the UDP mux
+struct TcpMuxConn {
+    udp: UDPMuxConn,
+    // ICE-TCP streams by remote address
+    streams: Mutex<HashMap<SocketAddr, TcpMuxStream>>,
+}
+
+struct TcpMuxStream {
+    writer: Arc<Mutex<OwnedWriteHalf>>,
+    reader: JoinHandle<()>,
+}
+
+impl TcpMuxConn {
+    async fn close_streams(&self) {
+        // dropping the write halves shuts the streams down
+        for (_, stream) in self.streams.lock().await.drain() {
+            stream.reader.abort();
+        }
+    }
+}
+
+/// Hands a new stream to the ICE agent its first binding request is for
+async fn accept_stream(
+    stream: TcpStream,
+    addr: SocketAddr,
+    conns: &Mutex<HashMap<String, Arc<TcpMuxConn>>>,
+) -> io::Result<()> {
+    let (mut reader, writer) = stream.into_split();
+
+    let first = tokio::time::timeout(FIRST_FRAME_TIMEOUT, read_frame(&mut reader))
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+    let ufrag = local_ufrag(&first)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not a binding request"))?;
+    let conn = conns.lock().await.get(&ufrag).cloned().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no ice agent with ufrag {}", ufrag),
+        )
+    })?;
+
+    // the reader removes the stream when it ends, so it's registered before the reader runs
+    let mut streams = conn.streams.lock().await;
+    conn.udp
+        .write_packet(&first, addr)
+        .await
+        .map_err(|err| io::Error::new(io::ErrorKind::BrokenPipe, err.to_string()))?;
+    let reader = tokio::spawn(enc!( (conn) async move {
+        if let Err(err) = read_frames(reader, addr, &conn.udp).await {
+            if err.kind() != io::ErrorKind::UnexpectedEof {
+                debug!("ice tcp connection from {} failed: {}", addr, err);
+            }
+        }
+        conn.streams.lock().await.remove(&addr);
+    }));
+    streams.insert(
+        addr,
+        TcpMuxStream {
+            writer: Arc::new(Mutex::new(writer)),
+            reader,
+        },
+    );
+
+    Ok(())
+}
+
+/// Reads the frames of a stream until it closes, handing them to the ICE agent
+async fn read_frames(
+    mut reader: OwnedReadHalf,
+    addr: SocketAddr,
+    udp: &UDPMuxConn,
+) -> io::Result<()> {
+    loop {
+        let frame = read_frame(&mut reader).await?;
+        if let Err(err) = udp.write_packet(&frame, addr).await {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, err.to_string()));
+        }
+    }
+}
+
+/// Reads one RFC 4571 frame, a 16 bit length then the packet
+async fn read_frame(reader: &mut OwnedReadHalf) -> io::Result<Vec<u8>> {
+    let length = reader.read_u16().await? as usize;
+    let mut frame = vec![0u8; length];
+    reader.read_exact(&mut frame).await?;
+    Ok(frame)
+}
+
+/// Local ufrag of a binding request, the USERNAME is "local:remote" from our side
+fn local_ufrag(frame: &[u8]) -> Option<String> {
+    let mut msg = Message::new();
+    msg.unmarshal_binary(frame).ok()?;
+    if msg.typ != BINDING_REQUEST {
+        return None;
+    }
+
+    let username = TextAttribute::get_from_as(&msg, ATTR_USERNAME).ok()?;
+    let (ufrag, _) = username.text.split_once(':')?;
+    Some(ufrag.to_owned())
+}
+
+#[async_trait]
+impl Conn for TcpMuxConn {
+    async fn connect(&self, _addr: SocketAddr) -> webrtc::util::Result<()> {
+        Err(io::Error::from(io::ErrorKind::Unsupported).into())
+    }
+
+    async fn recv(&self, _buf: &mut [u8]) -> webrtc::util::Result<usize> {
+        Err(io::Error::from(io::ErrorKind::Unsupported).into())
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> webrtc::util::Result<(usize, SocketAddr)> {
+        self.udp.recv_from(buf).await
+    }
+
+    async fn send(&self, _buf: &[u8]) -> webrtc::util::Result<usize> {
+        Err(io::Error::from(io::ErrorKind::Unsupported).into())
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> webrtc::util::Result<usize> {
+        let writer = match self.streams.lock().await.get(&target) {
+            Some(stream) => stream.writer.clone(),
+            None => return self.udp.send_to(buf, target).await,
+        };
+
+        let length = u16::try_from(buf.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "packet too large"))?;
+        let mut frame = Vec::with_capacity(2 + buf.len());
+        frame.extend_from_slice(&length.to_be_bytes());
+        frame.extend_from_slice(buf);
+        writer.lock().await.write_all(&frame).await?;
+        Ok(buf.len())
+    }
+
+    fn local_addr(&self) -> webrtc::util::Result<SocketAddr> {
+        self.udp.local_addr()
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    async fn close(&self) -> webrtc::util::Result<()> {
+        self.close_streams().await;
+        Conn::close(&self.udp).await
+    }
+
+    fn as_any(&self) -> &(dyn Any + Send + Sync) {
+        self
+    }
+}
+
+/// Passive ICE-TCP candidate on port for a UDP host candidate ("candidate:..." or without
+/// the prefix), None for other candidates
+pub fn tcp_candidate(candidate: &str, port: u16) -> Option<String> {
+    let udp =
+        unmarshal_candidate(candidate.strip_prefix("candidate:").unwrap_or(candidate)).ok()?;
+    if udp.candidate_type() != CandidateType::Host || !udp.network_type().is_udp() {
+        return None;
+    }
+
+    let tcp = CandidateHostConfig {
+        base_config: CandidateBaseConfig {
+            network: "tcp".to_owned(),
+            address: udp.address(),
+            port,
+            component: udp.component(),
+            ..Default::default()
+        },
+        tcp_type: TcpType::Passive,
+    }
+    .new_candidate_host()
+    .ok()?;
+
+    Some(format!("candidate:{}", tcp.marshal()))
+}
+
+/// Adds the passive ICE-TCP candidate of every UDP host candidate to a session description
+pub fn with_tcp_candidates(sdp: &str, port: u16) -> String {
+    let mut out = String::with_capacity(sdp.len());
+    for line in sdp.split_inclusive('\n') {
+        out.push_str(line);
+
+        let candidate = match line.trim_end().strip_prefix("a=") {
+            Some(candidate) if candidate.starts_with("candidate:") => candidate,
+            _ => continue,
+        };
+        if let Some(tcp) = tcp_candidate(candidate, port) {
+            out.push_str("a=");
+            out.push_str(&tcp);
+            out.push_str("\r\n");
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webrtc::stun::agent::TransactionId;
+
+    const UDP_HOST: &str = "candidate:1234 1 udp 2130706431 192.0.2.1 50000 typ host";
+
+    fn binding_request(username: &str) -> Vec<u8> {
+        let mut msg = Message::new();
+        msg.build(&[
+            Box::new(BINDING_REQUEST),
+            Box::new(TransactionId::new()),
+            Box::new(TextAttribute::new(ATTR_USERNAME, username.to_owned())),
+        ])
+        .unwrap();
+        msg.raw
+    }
+
+    fn frame(packet: &[u8]) -> Vec<u8> {
+        let mut frame = (packet.len() as u16).to_be_bytes().to_vec();
+        frame.extend_from_slice(packet);
+        frame
+    }
+
+    async fn mux() -> (Arc<TcpMux>, SocketAddr) {
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let udp = UDPMuxDefault::new(webrtc::ice::udp_mux::UDPMuxParams::new(socket));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        (TcpMux::new(udp, listener), addr)
+    }
+
+    #[test]
+    fn adds_passive_tcp_candidates_for_udp_hosts() {
+        let tcp = tcp_candidate(UDP_HOST, 3000).unwrap();
+        assert!(tcp.starts_with("candidate:"));
+        assert!(tcp.contains(" tcp "));
+        assert!(tcp.contains(" 192.0.2.1 3000 typ host tcptype passive"));
+
+        // the prefix is optional
+        assert_eq!(
+            tcp_candidate(UDP_HOST.strip_prefix("candidate:").unwrap(), 3000),
+            Some(tcp.clone())
+        );
+
+        let srflx =
+            "candidate:1 1 udp 1694498815 203.0.113.1 50000 typ srflx raddr 0.0.0.0 rport 50000";
+        assert_eq!(tcp_candidate(srflx, 3000), None);
+        assert_eq!(tcp_candidate(&tcp, 3000), None);
+        assert_eq!(tcp_candidate("candidate:nonsense", 3000), None);
+    }
+
+    #[test]
+    fn adds_tcp_candidates_to_sdp() {
+        let sdp = format!(
+            "m=audio 9 UDP/TLS/RTP/SAVPF 111\r\na={}\r\na=mid:0\r\n",
+            UDP_HOST
+        );
+        let lines: Vec<String> = with_tcp_candidates(&sdp, 3000)
+            .lines()
+            .map(str::to_owned)
+            .collect();
+
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[1], format!("a={}", UDP_HOST));
+        assert!(lines[2].starts_with("a=candidate:") && lines[2].ends_with("tcptype passive"));
+        assert_eq!(lines[3], "a=mid:0");
+    }
+
+    #[tokio::test]
+    async fn routes_streams_by_ufrag() {
+        let (mux, addr) = mux().await;
+        let conn = mux.clone().get_conn("local").await.unwrap();
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = binding_request("local:remote");
+        stream.write_all(&frame(&request)).await.unwrap();
+        stream.write_all(&frame(b"dtls")).await.unwrap();
+
+        let mut buf = vec![0u8; 1500];
+        let (n, from) = conn.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], &request[..]);
+        assert_eq!(from, stream.local_addr().unwrap());
+        let (n, _) = conn.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"dtls");
+
+        // replies to the stream's address go back on it, framed
+        conn.send_to(b"reply", from).await.unwrap();
+        let mut reply = [0u8; 7];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"\x00\x05reply");
+
+        mux.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn closes_streams_of_unknown_agents() {
+        let (mux, addr) = mux().await;
+        let _conn = mux.clone().get_conn("local").await.unwrap();
+
+        for first in [binding_request("other:remote"), b"not stun".to_vec()] {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(&frame(&first)).await.unwrap();
+            let mut buf = [0u8; 1];
+            assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+        }
+
+        mux.close().await.unwrap();
+    }
+}
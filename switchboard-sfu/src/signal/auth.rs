@@ -0,0 +1,245 @@
+use std::sync::Arc;
+
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{Error, Result};
+
+/// Claims of a join token
+/// Tokens are JWTs signed with HS256 (shared secret) or RS256 (private key, verified with the
+/// public key), `exp` is required
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Claims {
+    /// Session id the token grants access to
+    pub sid: String,
+    /// Identity shared with the other peers, takes precedence over the identity sent with join
+    #[serde(default)]
+    pub identity: Option<Value>,
+    /// Expiry (seconds since the unix epoch)
+    pub exp: u64,
+    /// Missing permissions are denied
+    #[serde(default)]
+    pub permissions: Permissions,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Debug)]
+pub struct Permissions {
+    #[serde(default)]
+    pub publish: bool,
+    #[serde(default)]
+    pub subscribe: bool,
+    /// Moderator, may change the role of any peer in the session
+    #[serde(default)]
+    pub admin: bool,
+}
+
+impl Permissions {
+    /// Permissions of peers when authentication is disabled
+    pub fn all() -> Permissions {
+        Permissions {
+            publish: true,
+            subscribe: true,
+            admin: true,
+        }
+    }
+}
+
+/// TokenVerifier checks join tokens against the configured keys
+#[derive(Default)]
+pub struct TokenVerifier {
+    keys: Vec<(DecodingKey, Validation)>,
+}
+
+impl TokenVerifier {
+    pub fn new() -> TokenVerifier {
+        TokenVerifier::default()
+    }
+
+    /// Accepts HS256 tokens signed with this secret
+    pub fn with_hs256_secret(mut self, secret: &[u8]) -> TokenVerifier {
+        self.keys.push((
+            DecodingKey::from_secret(secret),
+            Validation::new(Algorithm::HS256),
+        ));
+        self
+    }
+
+    /// Accepts RS256 tokens signed by the private key of this PEM encoded public key
+    /// A key that can't be parsed is a config error
+    pub fn with_rs256_public_key(mut self, pem: &[u8]) -> Result<TokenVerifier> {
+        let key = DecodingKey::from_rsa_pem(pem)
+            .map_err(|err| Error::Config(format!("invalid RS256 public key: {}", err)))?;
+        self.keys.push((key, Validation::new(Algorithm::RS256)));
+        Ok(self)
+    }
+
+    /// Verifies the signature and expiry of a token, returning its claims
+    pub fn verify(&self, token: &str) -> Result<Claims> {
+        let mut last_err = None;
+
+        for (key, validation) in &self.keys {
+            match decode::<Claims>(token, key, validation) {
+                Ok(data) => return Ok(data.claims),
+                Err(err) => last_err = Some(err),
+            }
+        }
+
+        Err(Error::Unauthorized(match last_err {
+            Some(err) => format!("invalid token: {}", err),
+            None => "no token keys configured".to_owned(),
+        }))
+    }
+
+    /// Verifies a token and checks that it grants access to the session
+    pub fn authorize_join(&self, token: Option<&str>, sid: &str) -> Result<Claims> {
+        let token = token.ok_or_else(|| Error::Unauthorized("missing token".to_owned()))?;
+        let claims = self.verify(token)?;
+
+        if claims.sid != sid {
+            return Err(Error::Unauthorized(format!(
+                "token is not valid for session {}",
+                sid
+            )));
+        }
+
+        Ok(claims)
+    }
+}
+
+/// Authentication of one signal connection
+#[derive(Clone)]
+pub struct ConnectionAuth {
+    pub verifier: Arc<TokenVerifier>,
+    /// Token from the websocket upgrade query string, used when join has none
+    pub upgrade_token: Option<String>,
+}
+
+/// Returns the token query parameter of a websocket upgrade request uri, percent-decoded
+pub fn token_from_query(query: Option<&str>) -> Option<String> {
+    query?
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("token="))
+        .map(percent_decode)
+}
+
+/// Decodes %XX escapes, malformed escapes are kept as they are
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = match bytes.get(i + 1..i + 3) {
+            Some(hex) if bytes[i] == b'%' => std::str::from_utf8(hex)
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    const SECRET: &[u8] = b"secret";
+
+    fn token(sid: &str, exp_offset: i64) -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let claims = Claims {
+            sid: sid.to_owned(),
+            identity: None,
+            exp: (now + exp_offset) as u64,
+            permissions: Permissions {
+                subscribe: true,
+                ..Default::default()
+            },
+        };
+        encode(
+            &Header::new(Algorithm::HS256),
+            &claims,
+            &EncodingKey::from_secret(SECRET),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn authorizes_valid_token() {
+        let verifier = TokenVerifier::new().with_hs256_secret(SECRET);
+
+        let claims = verifier
+            .authorize_join(Some(&token("room", 60)), "room")
+            .unwrap();
+        assert!(claims.permissions.subscribe);
+        assert!(!claims.permissions.publish);
+    }
+
+    #[test]
+    fn rejects_bad_tokens() {
+        let verifier = TokenVerifier::new().with_hs256_secret(SECRET);
+        let other = TokenVerifier::new().with_hs256_secret(b"other");
+
+        assert!(verifier.authorize_join(None, "room").is_err());
+        assert!(verifier
+            .authorize_join(Some(&token("room", 60)), "other-room")
+            .is_err());
+        assert!(verifier
+            .authorize_join(Some(&token("room", -3600)), "room")
+            .is_err());
+        assert!(other
+            .authorize_join(Some(&token("room", 60)), "room")
+            .is_err());
+    }
+
+    #[test]
+    fn reads_token_from_query() {
+        assert_eq!(
+            token_from_query(Some("a=1&token=abc.def")),
+            Some("abc.def".to_owned())
+        );
+        assert_eq!(token_from_query(Some("a=1")), None);
+        assert_eq!(token_from_query(None), None);
+    }
+
+    #[test]
+    fn decodes_query_token() {
+        assert_eq!(
+            token_from_query(Some("token=abc%2Edef%2eg-h_i")),
+            Some("abc.def.g-h_i".to_owned())
+        );
+        // malformed escapes are kept
+        assert_eq!(
+            token_from_query(Some("token=a%zz%2")),
+            Some("a%zz%2".to_owned())
+        );
+
+        let verifier = TokenVerifier::new().with_hs256_secret(SECRET);
+        let encoded = token("room", 60).replace('.', "%2E");
+        let query = format!("sid=room&token={}", encoded);
+        let token = token_from_query(Some(&query));
+        assert!(verifier.authorize_join(token.as_deref(), "room").is_ok());
+    }
+
+    #[test]
+    fn invalid_public_key_is_a_config_error() {
+        let result = TokenVerifier::new().with_rs256_public_key(b"not a key");
+        assert!(matches!(result, Err(Error::Config(_))));
+    }
+}
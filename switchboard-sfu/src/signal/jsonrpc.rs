@@ -5,21 +5,132 @@ use serde::{Deserialize, Serialize};
 use serde_json::{Map, Value};
 
 use futures_channel::{mpsc, oneshot};
+use tokio::io::{AsyncRead, AsyncWrite};
 
 use tokio_tungstenite::{tungstenite, WebSocketStream};
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(untagged)]
 pub enum Id {
     Uuid(String),
     Int(i32),
+    /// Used for errors about requests whose id couldn't be read
+    Null,
+}
+
+// JSON-RPC 2.0 error codes
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+
+// switchboard error codes (implementation defined server errors)
+pub const NOT_JOINED: i64 = -32000;
+pub const ALREADY_JOINED: i64 = -32001;
+pub const NEGOTIATION_FAILED: i64 = -32002;
+pub const ICE_FAILED: i64 = -32003;
+pub const ROUTING_FAILED: i64 = -32004;
+pub const UNAUTHORIZED: i64 = -32005;
+pub const FORBIDDEN: i64 = -32006;
+pub const CAPACITY_EXCEEDED: i64 = -32007;
+pub const RESUME_FAILED: i64 = -32008;
+pub const SHUTTING_DOWN: i64 = -32009;
+
+/// JSON-RPC 2.0 error object, sent in Response::error
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Error {
+    pub code: i64,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+impl Error {
+    pub fn new(code: i64, message: impl Into<String>) -> Error {
+        Error {
+            code,
+            message: message.into(),
+            data: None,
+        }
+    }
+
+    pub fn parse_error(err: impl std::fmt::Display) -> Error {
+        Error::new(PARSE_ERROR, format!("parse error: {}", err))
+    }
+
+    pub fn invalid_request(err: impl std::fmt::Display) -> Error {
+        Error::new(INVALID_REQUEST, format!("invalid request: {}", err))
+    }
+
+    pub fn method_not_found(method: &str) -> Error {
+        Error::new(METHOD_NOT_FOUND, format!("method not found: {}", method))
+    }
+
+    pub fn invalid_params(err: impl std::fmt::Display) -> Error {
+        Error::new(INVALID_PARAMS, format!("invalid params: {}", err))
+    }
+
+    pub fn internal(err: impl std::fmt::Display) -> Error {
+        Error::new(INTERNAL_ERROR, format!("internal error: {}", err))
+    }
+
+    pub fn not_joined() -> Error {
+        Error::new(NOT_JOINED, "peer has not joined a session")
+    }
+
+    pub fn already_joined() -> Error {
+        Error::new(ALREADY_JOINED, "peer already joined a session")
+    }
+
+    pub fn resume_failed() -> Error {
+        Error::new(RESUME_FAILED, "unknown or expired resume token")
+    }
+
+    pub fn shutting_down() -> Error {
+        Error::new(SHUTTING_DOWN, "server is shutting down")
+    }
+
+    pub fn negotiation_failed(err: impl std::fmt::Display) -> Error {
+        Error::new(NEGOTIATION_FAILED, format!("negotiation failed: {}", err))
+    }
+}
+
+impl From<crate::Error> for Error {
+    fn from(err: crate::Error) -> Error {
+        use crate::Error::*;
+
+        match err {
+            Negotiation(_) => Error::new(NEGOTIATION_FAILED, err.to_string()),
+            Ice(_) => Error::new(ICE_FAILED, err.to_string()),
+            Routing(_) => Error::new(ROUTING_FAILED, err.to_string()),
+            Unauthorized(_) => Error::new(UNAUTHORIZED, err.to_string()),
+            Forbidden(_) => Error::new(FORBIDDEN, err.to_string()),
+            CapacityExceeded(_) => Error::new(CAPACITY_EXCEEDED, err.to_string()),
+            // an unknown peer id in the request params, e.g. set_role
+            PeerNotFound(_) => Error::invalid_params(err),
+            PeerExists(_) => Error::already_joined(),
+            PeerConnection(_) | Signaling(_) | Tls(_) | Turn(_) | Config(_) | Io(_) => {
+                Error::internal(err)
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)
+    }
 }
 
+impl std::error::Error for Error {}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "snake_case")]
 pub struct Request {
     pub id: Id,
     pub method: String,
+    #[serde(default)]
     pub params: Map<String, Value>,
 
     #[serde(skip)]
@@ -36,10 +147,29 @@ pub struct Response {
     pub error: Option<Value>,
 }
 
+impl Response {
+    pub fn ok(id: Id, result: Map<String, Value>) -> Response {
+        Response {
+            id,
+            result: Some(result),
+            error: None,
+        }
+    }
+
+    pub fn err(id: Id, error: Error) -> Response {
+        Response {
+            id,
+            result: None,
+            error: Some(serde_json::to_value(error).unwrap()),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "snake_case")]
 pub struct Notification {
     pub method: String,
+    #[serde(default)]
     pub params: Map<String, Value>,
 }
 
@@ -50,22 +180,27 @@ pub enum Event {
     Request(Request),
     Response(Response),
     Notification(Notification),
+    /// Closes the websocket once the events before it are sent
+    #[serde(skip)]
+    Close,
 }
 
-pub type ReadStream = mpsc::UnboundedReceiver<anyhow::Result<Event>>;
-pub type WriteStream = mpsc::UnboundedSender<anyhow::Result<Event>>;
+pub type ReadStream = mpsc::UnboundedReceiver<crate::Result<Event>>;
+pub type WriteStream = mpsc::UnboundedSender<crate::Result<Event>>;
 
 /// This function processes the websocket stream into
 /// a writer and reader for jsonrpc::Event's
-pub async fn handle_messages(
-    stream: WebSocketStream<tokio::net::TcpStream>,
-) -> (ReadStream, WriteStream) {
+pub async fn handle_messages<S>(stream: WebSocketStream<S>) -> (ReadStream, WriteStream)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
     let (write, read) = stream.split();
 
-    let (read_tx, read_rx) = mpsc::unbounded::<anyhow::Result<Event>>();
-    let (write_tx, write_rx) = mpsc::unbounded::<anyhow::Result<Event>>();
+    let (read_tx, read_rx) = mpsc::unbounded::<crate::Result<Event>>();
+    let (write_tx, write_rx) = mpsc::unbounded::<crate::Result<Event>>();
 
     // Inbound message loop
+    let error_tx = write_tx.clone();
     tokio::spawn(async move {
         let mut incoming_fut = read
             .map_err(|err| error!("websocket error: {}", err))
@@ -74,32 +209,36 @@ pub async fn handle_messages(
                 trace!("websocket got msg {}", msg);
                 msg
             })
-            .map(|msg| serde_json::from_str::<Event>(msg.unwrap().to_text().unwrap()))
-            .map_err(|err| {
-                error!("error parsing json: {}", err);
-                err.into()
+            .filter_map(move |msg| {
+                // Malformed messages are answered with an error instead of closing the connection
+                let evt = msg
+                    .ok()
+                    .and_then(|msg| match parse_event(msg.to_text().unwrap_or("")) {
+                        Ok(evt) => Some(evt),
+                        Err(response) => {
+                            error!("error parsing json-rpc: {:?}", response.error);
+                            let _ = error_tx.unbounded_send(Ok(Event::Response(response)));
+                            None
+                        }
+                    });
+                future::ready(evt)
             })
-            .map_ok(|msg| {
+            .map(|msg| {
                 trace!("jsonrpc got event: {:#?}", msg);
-                msg
+                Ok(Ok(msg))
             })
-            .filter(|r| future::ready(r.is_ok()))
-            .map(Ok)
             .forward(read_tx);
 
         let mut outgoing_fut = write_rx
-            .map_ok(|evt| serde_json::to_value(&evt).unwrap())
-            .map_ok(|v| match v {
-                Value::Object(m) => {
-                    let mut m = m.clone();
-                    m.insert("jsonrpc".to_owned(), Value::String("2.0".to_owned()));
-                    Value::Object(m)
-                }
-                v => v.clone(),
+            .filter_map(|evt| {
+                future::ready(match evt.and_then(|evt| to_message(&evt)) {
+                    Ok(msg) => Some(Ok(msg)),
+                    Err(err) => {
+                        error!("error sending json-rpc event: {}", err);
+                        None
+                    }
+                })
             })
-            .map_ok(|v| serde_json::to_string(&v).unwrap())
-            .map_ok(|msg| tungstenite::Message::from(msg))
-            .map_err(|_| tungstenite::error::Error::ConnectionClosed)
             .forward(write);
 
         select! {
@@ -110,3 +249,102 @@ pub async fn handle_messages(
 
     (read_rx, write_tx)
 }
+
+/// Serializes an event into a json-rpc 2.0 websocket message
+fn to_message(evt: &Event) -> crate::Result<tungstenite::Message> {
+    if let Event::Close = evt {
+        return Ok(tungstenite::Message::Close(None));
+    }
+
+    let mut value =
+        serde_json::to_value(evt).map_err(|err| crate::Error::Signaling(err.to_string()))?;
+    if let Value::Object(m) = &mut value {
+        m.insert("jsonrpc".to_owned(), Value::String("2.0".to_owned()));
+    }
+    Ok(tungstenite::Message::from(value.to_string()))
+}
+
+/// Parses a websocket text message, returning the error response to send if it isn't a valid
+/// json-rpc message
+fn parse_event(msg: &str) -> Result<Event, Response> {
+    let value: Value = serde_json::from_str(msg)
+        .map_err(|err| Response::err(Id::Null, Error::parse_error(err)))?;
+
+    serde_json::from_value::<Event>(value.clone()).map_err(|err| {
+        let id = value
+            .get("id")
+            .and_then(|id| serde_json::from_value(id.clone()).ok())
+            .unwrap_or(Id::Null);
+        Response::err(id, Error::invalid_request(err))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error_code(response: &Response) -> i64 {
+        response.error.as_ref().unwrap()["code"].as_i64().unwrap()
+    }
+
+    #[test]
+    fn malformed_json_is_a_parse_error() {
+        let response = parse_event("{\"jsonrpc\": \"2.0\", ").unwrap_err();
+        assert_eq!(error_code(&response), PARSE_ERROR);
+        assert!(matches!(response.id, Id::Null));
+    }
+
+    #[test]
+    fn non_rpc_json_is_an_invalid_request() {
+        let response = parse_event("{\"id\": 7, \"result\": 1}").unwrap_err();
+        assert_eq!(error_code(&response), INVALID_REQUEST);
+        assert!(matches!(response.id, Id::Int(7)));
+
+        let response = parse_event("[1, 2]").unwrap_err();
+        assert_eq!(error_code(&response), INVALID_REQUEST);
+        assert!(matches!(response.id, Id::Null));
+    }
+
+    #[test]
+    fn parses_requests_and_notifications() {
+        let request = parse_event("{\"id\": 1, \"method\": \"join\", \"params\": {}}");
+        assert!(matches!(request, Ok(Event::Request(r)) if r.method == "join"));
+
+        let notification = parse_event("{\"method\": \"trickle\"}");
+        assert!(matches!(notification, Ok(Event::Notification(n)) if n.method == "trickle"));
+    }
+
+    #[test]
+    fn maps_errors_to_codes() {
+        let webrtc_error = || webrtc::Error::new("failed".to_owned());
+        let peer_id = uuid::Uuid::new_v4();
+        let cases = [
+            (
+                crate::Error::Negotiation(webrtc_error()),
+                NEGOTIATION_FAILED,
+            ),
+            (crate::Error::Ice(webrtc_error()), ICE_FAILED),
+            (crate::Error::Routing("r".to_owned()), ROUTING_FAILED),
+            (crate::Error::Unauthorized("u".to_owned()), UNAUTHORIZED),
+            (crate::Error::Forbidden("f".to_owned()), FORBIDDEN),
+            (
+                crate::Error::CapacityExceeded("c".to_owned()),
+                CAPACITY_EXCEEDED,
+            ),
+            (crate::Error::PeerNotFound(peer_id), INVALID_PARAMS),
+            (crate::Error::PeerExists(peer_id), ALREADY_JOINED),
+            (crate::Error::PeerConnection(webrtc_error()), INTERNAL_ERROR),
+            (crate::Error::Signaling("s".to_owned()), INTERNAL_ERROR),
+            (crate::Error::Config("c".to_owned()), INTERNAL_ERROR),
+        ];
+
+        for (err, code) in cases {
+            let message = err.to_string();
+            assert_eq!(Error::from(err).code, code, "{}", message);
+        }
+        let negotiation = Error::from(crate::Error::Negotiation(webrtc_error()));
+        assert_eq!(negotiation.message, "negotiation failed: failed");
+        assert_eq!(Error::resume_failed().code, RESUME_FAILED);
+        assert_eq!(Error::shutting_down().code, SHUTTING_DOWN);
+    }
+}
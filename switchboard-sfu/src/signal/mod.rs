@@ -1,5 +1,8 @@
+pub mod auth;
 pub mod jsonrpc;
 pub mod server;
+#[allow(clippy::module_inception)]
 pub mod signal;
+pub mod tls;
 
 pub use server::*;
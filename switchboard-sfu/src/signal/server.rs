@@ -1,148 +1,967 @@
-use futures_util::{SinkExt, StreamExt};
+use async_mutex::Mutex;
+use enclose::enc;
+use futures_channel::oneshot;
+use futures_util::{future, SinkExt, StreamExt};
 use log::*;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::WebSocketStream;
+use uuid::Uuid;
 
 use super::*;
 
-use crate::sfu::coordinator::{Coordinator, LocalCoordinator};
+use super::auth::{self, ConnectionAuth, Permissions, TokenVerifier};
+use super::tls::TlsConfig;
+use crate::sfu::coordinator::{Coordinator, CoordinatorConfig, LocalCoordinator};
 use crate::sfu::peer;
 use crate::sfu::session;
 use crate::sfu::session::{LocalSession, Session};
+use crate::turn::{IceServers, TurnServer};
+use crate::{Result, ServerConfig};
 
-/// Spawns a tokio tcp server
-pub async fn run_server(addr: &str) {
-    let coordinator: Arc<LocalCoordinator<LocalSession>> = LocalCoordinator::new();
+/// Runs the signal server described by the config, listening on every address of
+/// config.listen, until SIGINT/SIGTERM shut it down gracefully
+/// The NAT 1:1 IPs are detected first if enabled, then the embedded TURN server is started if
+/// configured and handed to clients
+/// With auth keys every join must carry a valid token for its session, with tls connections
+/// are served over TLS (wss://)
+pub async fn run_server(mut config: ServerConfig) -> Result<()> {
+    config.detect_nat_ips().await;
+    let mut builder = SwitchboardServer::from_config(&config)?;
+    let mut turn = None;
+    if config.turn.enabled() {
+        turn = Some(TurnServer::start(&config.turn, config.turn_public_ip()?).await?);
+    }
+    if let Some(turn) = &turn {
+        builder = builder.ice_servers(config.ice_servers().with_client_server(turn.ice_server()));
+    }
+    let mut server = builder.start().await?;
+
+    let signalled = tokio::select! {
+        _ = server.wait() => false,
+        _ = shutdown_signal() => true,
+    };
+    if signalled {
+        server.shutdown().await;
+    }
+    if let Some(turn) = turn {
+        turn.close().await;
+    }
 
-    // Create the event loop and TCP listener we'll accept connections on.
-    let try_socket = TcpListener::bind(&addr).await;
-    let listener = try_socket.expect("Failed to bind");
-    info!("Listening on: {}", addr);
+    Ok(())
+}
+
+/// Resolves on SIGINT (ctrl-c) or SIGTERM
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => info!("got SIGINT"),
+                    _ = terminate.recv() => info!("got SIGTERM"),
+                }
+                return;
+            }
+            Err(err) => warn!("couldn't listen for SIGTERM: {}", err),
+        }
+    }
 
-    while let Ok((stream, _)) = listener.accept().await {
-        tokio::spawn(accept_connection::<
-            LocalCoordinator<LocalSession>,
-            LocalSession,
-        >(coordinator.clone(), stream));
+    match tokio::signal::ctrl_c().await {
+        Ok(()) => info!("got SIGINT"),
+        Err(err) => {
+            error!("couldn't listen for SIGINT: {}", err);
+            future::pending::<()>().await
+        }
     }
 }
 
-/// Handles a websocket connection for a given Coordinator<S>
-async fn accept_connection<C, S>(coordinator: Arc<C>, stream: TcpStream)
+const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_RECONNECT_AFTER: Duration = Duration::from_secs(1);
+const DEFAULT_RESUME_GRACE: Duration = Duration::from_secs(30);
+/// How often shutdown checks whether the sessions are empty
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Builds the PeerConfig of a join request, loopback, auto_subscribe, identity and role are
+/// then set from the join
+pub type PeerConfigFactory = Arc<dyn Fn(&signal::JoinMsg) -> peer::PeerConfig + Send + Sync>;
+
+/// Called with every ServerEvent
+pub type EventHook = Arc<dyn Fn(ServerEvent) + Send + Sync>;
+
+/// Peer lifecycle events, for apps embedding the server
+#[derive(Clone, Debug)]
+pub enum ServerEvent {
+    PeerJoined {
+        session_id: session::Id,
+        peer_id: peer::Id,
+        identity: Option<serde_json::Value>,
+    },
+    PeerLeft {
+        session_id: session::Id,
+        peer_id: peer::Id,
+    },
+}
+
+/// Handle of a running signal server, see SwitchboardServer::builder
+pub struct SwitchboardServer<C, S> {
+    server: Arc<Server<C, S>>,
+    local_addrs: Vec<SocketAddr>,
+    accept_loops: Vec<JoinHandle<()>>,
+}
+
+impl SwitchboardServer<LocalCoordinator<LocalSession>, LocalSession> {
+    /// Builder for a server with a LocalCoordinator and the default PeerConfig
+    pub fn builder() -> SwitchboardServerBuilder<LocalCoordinator<LocalSession>, LocalSession> {
+        SwitchboardServerBuilder::new(LocalCoordinator::new(CoordinatorConfig::default()))
+    }
+
+    /// Builder set up from a ServerConfig (validated first)
+    pub fn from_config(
+        config: &ServerConfig,
+    ) -> Result<SwitchboardServerBuilder<LocalCoordinator<LocalSession>, LocalSession>> {
+        config.validate()?;
+
+        let mut builder =
+            SwitchboardServerBuilder::new(LocalCoordinator::new(config.coordinator_config()))
+                .peer_config(config.peer_config()?);
+        if let Some(verifier) = config.token_verifier()? {
+            builder = builder.token_verifier(verifier);
+        }
+        if let Some(tls) = &config.tls {
+            builder = builder.tls(tls.clone());
+        }
+        for addr in &config.listen {
+            builder = builder.bind(addr);
+        }
+        builder = builder
+            .drain_timeout(Duration::from_secs(config.shutdown.drain_timeout_secs))
+            .reconnect_after(Duration::from_millis(config.shutdown.reconnect_after_ms))
+            .resume_grace(Duration::from_secs(config.resume.grace_secs))
+            .ice_servers(config.ice_servers());
+
+        Ok(builder)
+    }
+}
+
+impl<C, S> SwitchboardServer<C, S>
 where
-    C: Coordinator<S>,
-    S: Session,
+    C: Coordinator<S> + Send + Sync + 'static,
+    S: Session + Send + Sync + 'static,
 {
-    let addr = stream
-        .peer_addr()
-        .expect("connected streams should have a peer address");
+    /// Addresses of the listeners the server accepts on
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    pub fn coordinator(&self) -> Arc<C> {
+        self.server.coordinator.clone()
+    }
+
+    /// Serves a connection accepted from your own listener (TLS and websocket handshakes
+    /// included)
+    /// The connection is closed right away once the server is shutting down
+    pub async fn serve_connection(&self, stream: TcpStream) {
+        if self.server.shutting_down() {
+            info!("shutting down, refusing connection");
+            return;
+        }
+        accept_connection(self.server.clone(), stream).await
+    }
+
+    /// Serves a websocket already upgraded by an http server (hyper, axum...), query is the
+    /// query string of the upgrade request (for the join token)
+    pub async fn serve_upgraded<T>(&self, io: T, query: Option<&str>)
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        if self.server.shutting_down() {
+            info!("shutting down, refusing websocket");
+            return;
+        }
+        let ws_stream = WebSocketStream::from_raw_socket(io, Role::Server, None).await;
+        serve_websocket(&self.server, ws_stream, auth::token_from_query(query)).await
+    }
+
+    /// Runs until every listener fails
+    pub async fn wait(&mut self) {
+        future::join_all(self.accept_loops.iter_mut()).await;
+    }
+
+    /// Shuts the server down gracefully
+    /// New connections are refused and every client gets a server_shutdown notification, then
+    /// sessions get up to the drain timeout to empty before the remaining connections end and
+    /// their peers are closed
+    pub async fn shutdown(self) {
+        info!(
+            "shutting down, draining sessions for up to {:?}",
+            self.server.drain_timeout
+        );
+        for accept_loop in &self.accept_loops {
+            accept_loop.abort();
+        }
+        self.server.shutdown.send_replace(ShutdownState::Draining);
+        // disconnected peers won't come back to a draining server
+        self.server.leave_parked().await;
+
+        let drained = tokio::time::timeout(self.server.drain_timeout, async {
+            while self.server.coordinator.session_count().await > 0 {
+                tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+            }
+        })
+        .await;
+        if drained.is_err() {
+            warn!("drain timeout reached, closing the remaining peers");
+        }
+
+        self.server.shutdown.send_replace(ShutdownState::Closed);
+        let mut connections = self.server.connections.subscribe();
+        let _ = connections.wait_for(|count| *count == 0).await;
+        info!("shutdown complete");
+    }
+}
+
+/// Builder of a SwitchboardServer
+pub struct SwitchboardServerBuilder<C, S> {
+    drain_timeout: Duration,
+    reconnect_after: Duration,
+    resume_grace: Duration,
+    coordinator: Arc<C>,
+    peer_config: PeerConfigFactory,
+    verifier: Option<TokenVerifier>,
+    tls: Option<TlsConfig>,
+    bind: Vec<String>,
+    listeners: Vec<TcpListener>,
+    on_event: Option<EventHook>,
+    ice_servers: Option<IceServers>,
+    _session: PhantomData<fn() -> S>,
+}
+
+impl<C, S> SwitchboardServerBuilder<C, S>
+where
+    C: Coordinator<S> + Send + Sync + 'static,
+    S: Session + Send + Sync + 'static,
+{
+    pub fn new(coordinator: Arc<C>) -> SwitchboardServerBuilder<C, S> {
+        SwitchboardServerBuilder {
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            reconnect_after: DEFAULT_RECONNECT_AFTER,
+            resume_grace: DEFAULT_RESUME_GRACE,
+            coordinator,
+            peer_config: Arc::new(|_| peer::PeerConfig::default()),
+            verifier: None,
+            tls: None,
+            bind: vec![],
+            listeners: vec![],
+            on_event: None,
+            ice_servers: None,
+            _session: PhantomData,
+        }
+    }
+
+    /// Replaces the coordinator (and with it the session type)
+    pub fn coordinator<C2, S2>(self, coordinator: Arc<C2>) -> SwitchboardServerBuilder<C2, S2>
+    where
+        C2: Coordinator<S2> + Send + Sync + 'static,
+        S2: Session + Send + Sync + 'static,
+    {
+        SwitchboardServerBuilder {
+            drain_timeout: self.drain_timeout,
+            reconnect_after: self.reconnect_after,
+            resume_grace: self.resume_grace,
+            coordinator,
+            peer_config: self.peer_config,
+            verifier: self.verifier,
+            tls: self.tls,
+            bind: self.bind,
+            listeners: self.listeners,
+            on_event: self.on_event,
+            ice_servers: self.ice_servers,
+            _session: PhantomData,
+        }
+    }
+
+    /// Uses the same PeerConfig for every peer
+    pub fn peer_config(mut self, cfg: peer::PeerConfig) -> Self {
+        self.peer_config = Arc::new(move |_| cfg.clone());
+        self
+    }
+
+    /// Builds the PeerConfig of each peer from its join request
+    pub fn peer_config_factory<F>(mut self, factory: F) -> Self
+    where
+        F: Fn(&signal::JoinMsg) -> peer::PeerConfig + Send + Sync + 'static,
+    {
+        self.peer_config = Arc::new(factory);
+        self
+    }
+
+    /// Requires a valid join token for every join
+    pub fn token_verifier(mut self, verifier: TokenVerifier) -> Self {
+        self.verifier = Some(verifier);
+        self
+    }
+
+    /// Serves the listeners over TLS (wss://)
+    pub fn tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Listens on the address once started, can be called more than once
+    pub fn bind(mut self, addr: impl Into<String>) -> Self {
+        self.bind.push(addr.into());
+        self
+    }
+
+    /// Accepts connections from an already bound listener
+    pub fn listener(mut self, listener: TcpListener) -> Self {
+        self.listeners.push(listener);
+        self
+    }
+
+    /// How long shutdown waits for sessions to empty before closing their peers
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// Reconnect hint sent to clients when the server shuts down
+    pub fn reconnect_after(mut self, delay: Duration) -> Self {
+        self.reconnect_after = delay;
+        self
+    }
+
+    /// How long the peer of a dropped connection is kept for a resume, zero disables resuming
+    pub fn resume_grace(mut self, grace: Duration) -> Self {
+        self.resume_grace = grace;
+        self
+    }
+
+    /// STUN/TURN servers issued with every join, for the server side peer connections
+    /// (replacing the PeerConfig's) and for the client in the join response
+    pub fn ice_servers(mut self, servers: IceServers) -> Self {
+        self.ice_servers = Some(servers);
+        self
+    }
+
+    pub fn on_event<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(ServerEvent) + Send + Sync + 'static,
+    {
+        self.on_event = Some(Arc::new(hook));
+        self
+    }
+
+    /// Binds the addresses and starts accepting on every listener
+    /// Without listeners connections are only served through serve_connection/serve_upgraded
+    pub async fn start(self) -> Result<SwitchboardServer<C, S>> {
+        let server = Arc::new(Server {
+            coordinator: self.coordinator,
+            peer_config: self.peer_config,
+            verifier: self.verifier.map(Arc::new),
+            acceptor: self.tls.map(|tls| tls.acceptor()).transpose()?,
+            on_event: self.on_event,
+            drain_timeout: self.drain_timeout,
+            reconnect_after: self.reconnect_after,
+            resume_grace: self.resume_grace,
+            ice_servers: self.ice_servers,
+            shutdown: watch::channel(ShutdownState::Running).0,
+            connections: watch::channel(0).0,
+            resumable: Mutex::new(HashMap::new()),
+        });
+
+        // Bind every address before accepting, so a bad one fails startup
+        let mut listeners = self.listeners;
+        for addr in &self.bind {
+            listeners.push(TcpListener::bind(addr).await?);
+        }
+
+        let mut local_addrs = vec![];
+        let mut accept_loops = vec![];
+        for listener in listeners {
+            let addr = listener.local_addr()?;
+            info!(
+                "Listening on: {}://{}",
+                if server.acceptor.is_some() {
+                    "wss"
+                } else {
+                    "ws"
+                },
+                addr
+            );
+            local_addrs.push(addr);
+
+            accept_loops.push(tokio::spawn(enc!( (server) async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    tokio::spawn(accept_connection(server.clone(), stream));
+                }
+            })));
+        }
+
+        Ok(SwitchboardServer {
+            server,
+            local_addrs,
+            accept_loops,
+        })
+    }
+}
+
+/// State shared by every connection of a server
+struct Server<C, S> {
+    coordinator: Arc<C>,
+    peer_config: PeerConfigFactory,
+    verifier: Option<Arc<TokenVerifier>>,
+    acceptor: Option<TlsAcceptor>,
+    on_event: Option<EventHook>,
+    drain_timeout: Duration,
+    reconnect_after: Duration,
+    resume_grace: Duration,
+    ice_servers: Option<IceServers>,
+    shutdown: watch::Sender<ShutdownState>,
+    // number of connections being served
+    connections: watch::Sender<usize>,
+    // joined peers by resume token
+    resumable: Mutex<HashMap<String, Resumable<S>>>,
+}
+
+impl<C, S> Server<C, S>
+where
+    C: Coordinator<S> + Send + Sync + 'static,
+    S: Session + Send + Sync + 'static,
+{
+    /// Whether shutdown began, no peer joins or resumes from then on
+    fn shutting_down(&self) -> bool {
+        *self.shutdown.borrow() != ShutdownState::Running
+    }
+
+    fn emit(&self, evt: ServerEvent) {
+        if let Some(on_event) = &self.on_event {
+            on_event(evt);
+        }
+    }
+
+    /// Makes a peer resumable, returns its resume token (None when resuming is disabled)
+    /// The takeover sender ends the connection serving the peer once it resumes elsewhere
+    async fn register(
+        &self,
+        peer: &Arc<peer::Peer>,
+        session: &session::SessionHandle<S>,
+        permissions: Permissions,
+        takeover: oneshot::Sender<()>,
+    ) -> Option<String> {
+        if self.resume_grace.is_zero() {
+            return None;
+        }
+
+        let token = Uuid::new_v4().simple().to_string();
+        self.resumable.lock().await.insert(
+            token.clone(),
+            Resumable {
+                peer: peer.clone(),
+                session: session.clone(),
+                permissions,
+                state: ResumableState::Attached(takeover),
+            },
+        );
+        Some(token)
+    }
+
+    /// Takes a resumable peer away from its connection, or out of its grace period
+    async fn take_over(
+        &self,
+        token: &str,
+    ) -> Option<(Arc<peer::Peer>, session::SessionHandle<S>, Permissions)> {
+        let resumable = self.resumable.lock().await.remove(token)?;
+        match resumable.state {
+            ResumableState::Attached(takeover) => {
+                let _ = takeover.send(());
+            }
+            ResumableState::Parked(expiry) => expiry.abort(),
+        }
+        Some((resumable.peer, resumable.session, resumable.permissions))
+    }
+
+    /// Called when the connection of a resumable peer ends, the peer is either parked until
+    /// it resumes or the grace period ends, or removed right away
+    /// Returns false if the peer already resumed on another connection
+    async fn release(self: &Arc<Self>, token: &str, park: bool) -> bool {
+        let mut resumable = self.resumable.lock().await;
+        let entry = match resumable.get_mut(token) {
+            Some(entry) => entry,
+            None => return false,
+        };
+
+        if park {
+            info!(
+                "peer id={} disconnected, resumable for {:?}",
+                entry.peer.id, self.resume_grace
+            );
+            entry.peer.signal_tx.detach();
+            let server = self.clone();
+            let token = token.to_owned();
+            let expiry = tokio::spawn(async move {
+                tokio::time::sleep(server.resume_grace).await;
+                let expired = server.resumable.lock().await.remove(&token);
+                if let Some(expired) = expired {
+                    info!("peer id={} wasn't resumed in time", expired.peer.id);
+                    server.leave(&expired.peer, &expired.session).await;
+                }
+            });
+            entry.state = ResumableState::Parked(expiry);
+            return true;
+        }
+
+        if let Some(entry) = resumable.remove(token) {
+            drop(resumable);
+            self.leave(&entry.peer, &entry.session).await;
+        }
+        true
+    }
+
+    /// Removes every parked peer without waiting for its grace period
+    async fn leave_parked(&self) {
+        let parked: Vec<Resumable<S>> = {
+            let mut resumable = self.resumable.lock().await;
+            let tokens: Vec<String> = resumable
+                .iter()
+                .filter(|(_, entry)| matches!(entry.state, ResumableState::Parked(_)))
+                .map(|(token, _)| token.clone())
+                .collect();
+            tokens
+                .iter()
+                .filter_map(|token| resumable.remove(token))
+                .collect()
+        };
+
+        for entry in parked {
+            if let ResumableState::Parked(expiry) = &entry.state {
+                expiry.abort();
+            }
+            self.leave(&entry.peer, &entry.session).await;
+        }
+    }
+
+    /// Removes a peer from its session for good, closing its peer connections
+    async fn leave(&self, peer: &peer::Peer, session: &session::SessionHandle<S>) {
+        if let Err(err) = session.remove_peer(peer.id).await {
+            error!("error removing peer: {}", err);
+        }
+        self.emit(ServerEvent::PeerLeft {
+            session_id: session.id(),
+            peer_id: peer.id,
+        });
+
+        self.coordinator.cleanup_session(session.id()).await;
+    }
+}
+
+/// A joined peer that can be resumed on a new connection
+struct Resumable<S> {
+    peer: Arc<peer::Peer>,
+    session: session::SessionHandle<S>,
+    permissions: Permissions,
+    state: ResumableState,
+}
+
+enum ResumableState {
+    /// Served by a connection, which ends when the peer resumes elsewhere
+    Attached(oneshot::Sender<()>),
+    /// Its connection dropped, it leaves once the grace period ends
+    Parked(JoinHandle<()>),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum ShutdownState {
+    Running,
+    /// Clients were asked to leave
+    Draining,
+    /// Every connection ends
+    Closed,
+}
+
+/// Counts a connection as served until dropped
+struct ConnectionGuard<'a>(&'a watch::Sender<usize>);
+
+impl<'a> ConnectionGuard<'a> {
+    fn new(connections: &'a watch::Sender<usize>) -> ConnectionGuard<'a> {
+        connections.send_modify(|count| *count += 1);
+        ConnectionGuard(connections)
+    }
+}
+
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        self.0.send_modify(|count| *count -= 1);
+    }
+}
+
+/// Handles a tcp connection for a given Coordinator<S>, doing the TLS handshake first if
+/// there's an acceptor
+async fn accept_connection<C, S>(server: Arc<Server<C, S>>, stream: TcpStream)
+where
+    C: Coordinator<S> + Send + Sync + 'static,
+    S: Session + Send + Sync + 'static,
+{
+    let addr = match stream.peer_addr() {
+        Ok(addr) => addr,
+        Err(err) => {
+            error!("connection has no peer address: {}", err);
+            return;
+        }
+    };
     info!("Peer address: {}", addr);
 
-    let ws_stream = tokio_tungstenite::accept_async(stream)
-        .await
-        .expect("Error during the websocket handshake occurred");
+    match &server.acceptor {
+        Some(acceptor) => match acceptor.accept(stream).await {
+            Ok(stream) => accept_websocket(&server, addr, stream).await,
+            Err(err) => error!("tls handshake with {} failed: {}", addr, err),
+        },
+        None => accept_websocket(&server, addr, stream).await,
+    }
+}
+
+/// Does the websocket handshake of a connection for a given Coordinator<S>
+async fn accept_websocket<C, S, T>(server: &Arc<Server<C, S>>, addr: SocketAddr, stream: T)
+where
+    C: Coordinator<S> + Send + Sync + 'static,
+    S: Session + Send + Sync + 'static,
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // The join token may be passed in the websocket url
+    let mut upgrade_token = None;
+    // the callback signature is defined by tungstenite
+    #[allow(clippy::result_large_err)]
+    let capture_token = |req: &Request, res: Response| {
+        upgrade_token = auth::token_from_query(req.uri().query());
+        Ok(res)
+    };
+
+    let ws_stream = match tokio_tungstenite::accept_hdr_async(stream, capture_token).await {
+        Ok(ws_stream) => ws_stream,
+        Err(err) => {
+            error!("websocket handshake with {} failed: {}", addr, err);
+            return;
+        }
+    };
 
     info!("New WebSocket connection: {}", addr);
+    serve_websocket(server, ws_stream, upgrade_token).await;
+}
+
+/// Handles a websocket connection for a given Coordinator<S>
+async fn serve_websocket<C, S, T>(
+    server: &Arc<Server<C, S>>,
+    ws_stream: WebSocketStream<T>,
+    upgrade_token: Option<String>,
+) where
+    C: Coordinator<S> + Send + Sync + 'static,
+    S: Session + Send + Sync + 'static,
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let _guard = ConnectionGuard::new(&server.connections);
 
     let (rpc_rx, rpc_tx) = jsonrpc::handle_messages(ws_stream).await;
-    let (sig_rx, mut sig_tx) = signal::handle_messages(rpc_rx, rpc_tx).await;
+    let auth = server.verifier.clone().map(|verifier| ConnectionAuth {
+        verifier,
+        upgrade_token,
+    });
+    let (sig_rx, mut sig_tx) = signal::handle_messages(rpc_rx, rpc_tx, auth).await;
+
+    // Asks the client to leave once the server starts draining
+    let mut shutdown = server.shutdown.subscribe();
+    let notification = signal::ServerShutdownNotification {
+        drain_timeout_ms: server.drain_timeout.as_millis() as u64,
+        reconnect_after_ms: server.reconnect_after.as_millis() as u64,
+    };
+    let notify_shutdown = tokio::spawn(enc!( (sig_tx) async move {
+        if shutdown.wait_for(|state| *state != ShutdownState::Running).await.is_ok() {
+            let _ = sig_tx.unbounded_send(Ok(signal::Event::ServerShutdown(notification)));
+        }
+    }));
+
+    event_loop(server, sig_rx, sig_tx.clone()).await;
+    notify_shutdown.abort();
 
-    event_loop(coordinator, sig_rx, sig_tx.clone()).await;
+    // gone already unless the server ended the loop
+    let _ = sig_tx.unbounded_send(Ok(signal::Event::Close));
 
     error!("event loop closed");
-    sig_tx.close().await.expect("closed signal tx");
+    let _ = sig_tx.close().await;
 
     info!("client disconnected");
 }
 
-/// Event loop for each signal connection
-pub async fn event_loop<C, S>(
-    coordinator: Arc<C>,
-    mut rx: signal::ReadStream,
+/// Event loop for each signal connection, it ends when the client disconnects or leaves, when
+/// the server shuts down or when the peer resumes on another connection
+/// A peer whose client disconnected stays in its session for the resume grace period
+async fn event_loop<C, S>(
+    server: &Arc<Server<C, S>>,
+    rx: signal::ReadStream,
     tx: signal::WriteStream,
 ) where
-    C: Coordinator<S>,
-    S: Session,
+    C: Coordinator<S> + Send + Sync + 'static,
+    S: Session + Send + Sync + 'static,
 {
-    let mut peer: Option<Arc<peer::Peer>> = None;
-    let mut joined_session: Option<session::SessionHandle<S>> = None;
+    let mut joined: Option<(Arc<peer::Peer>, session::SessionHandle<S>)> = None;
+    let mut resume_token: Option<String> = None;
+    let mut left = false;
+    // Without authentication every peer is a moderator
+    let mut permissions = Permissions::all();
+
+    let coordinator = &server.coordinator;
+    let mut shutdown = server.shutdown.subscribe();
+    let closed = Box::pin(async move {
+        let _ = shutdown
+            .wait_for(|state| *state == ShutdownState::Closed)
+            .await;
+    });
+    // Fires when the peer resumes on another connection
+    let (takeover_tx, takeover_rx) = oneshot::channel::<()>();
+    let mut takeover_tx = Some(takeover_tx);
+    let taken_over = Box::pin(async move {
+        if takeover_rx.await.is_err() {
+            future::pending::<()>().await
+        }
+    });
+    let mut rx = rx.take_until(future::select(closed, taken_over));
 
     while let Some(Ok(evt)) = rx.next().await {
         match evt {
             signal::Event::JoinRequest(res, join) => {
-                info!("got join request: {:#?}", join);
-
-                let session = coordinator.get_or_create_session(join.sid).await;
+                info!("got join request for session {}", join.sid);
 
-                let p = peer::Peer::new(
-                    tx.clone(),
-                    session.write_channel(),
-                    peer::PeerConfig::default(),
-                )
-                .await
-                .expect("Error creating peer");
+                // the token's permissions apply once its join succeeds
+                let join_permissions = join.claims.as_ref().map(|claims| claims.permissions);
+                let result = match &joined {
+                    Some(_) => Err(jsonrpc::Error::already_joined()),
+                    None if server.shutting_down() => Err(jsonrpc::Error::shutting_down()),
+                    None => {
+                        let peer_config = (server.peer_config)(&join);
+                        join_session(
+                            &**coordinator,
+                            peer_config,
+                            server.ice_servers.as_ref(),
+                            &tx,
+                            join,
+                        )
+                        .await
+                    }
+                };
+                let result = match result {
+                    Ok((peer, session, mut response)) => {
+                        if let Some(join_permissions) = join_permissions {
+                            permissions = join_permissions;
+                        }
+                        server.emit(ServerEvent::PeerJoined {
+                            session_id: session.id(),
+                            peer_id: peer.id,
+                            identity: peer.identity.clone(),
+                        });
+                        if let Some(takeover) = takeover_tx.take() {
+                            response.resume_token = server
+                                .register(&peer, &session, permissions, takeover)
+                                .await;
+                            resume_token = response.resume_token.clone();
+                        }
+                        joined = Some((peer, session));
+                        Ok(response)
+                    }
+                    Err(err) => Err(err),
+                };
+                let _ = res.send(result);
+            }
 
-                let answer = p.publisher_get_answer_for_offer(join.offer).await;
-                if let Err(err) = &answer {
-                    error!("Error with join offer {}", err);
+            signal::Event::Resume(res, resume) => {
+                let resumed = match &joined {
+                    Some(_) => Err(jsonrpc::Error::already_joined()),
+                    None if server.shutting_down() => Err(jsonrpc::Error::shutting_down()),
+                    None => server
+                        .take_over(&resume.token)
+                        .await
+                        .ok_or_else(jsonrpc::Error::resume_failed),
+                };
+                let (peer, session, resumed_permissions) = match resumed {
+                    Ok(resumed) => resumed,
+                    Err(err) => {
+                        let _ = res.send(Err(err));
+                        continue;
+                    }
                 };
+                info!("peer id={} resumed", peer.id);
 
-                info!("answer created ");
-                session
-                    .add_peer(p.id, p.clone())
-                    .await
-                    .expect("error adding peer to session");
+                // notifications queued while disconnected go to the new connection
+                peer.signal_tx.attach(tx.clone());
+                permissions = resumed_permissions;
+                if let Some(takeover) = takeover_tx.take() {
+                    resume_token = server
+                        .register(&peer, &session, permissions, takeover)
+                        .await;
+                }
+                let _ = res.send(Ok(signal::ResumeResponse {
+                    peer_id: peer.id,
+                    role: peer.role().await,
+                    participants: session.participants().await,
+                    resume_token: resume_token.clone(),
+                }));
 
-                peer = Some(p.clone());
-                joined_session = Some(session);
+                if resume.ice_restart && peer.role().await.subscribes() {
+                    if let Err(err) = peer.restart_subscriber_ice().await {
+                        error!("error restarting ice: {}", err);
+                    }
+                }
+                joined = Some((peer, session));
+            }
 
-                res.send(answer.unwrap()).expect("error sending response");
+            signal::Event::Leave => {
+                left = true;
+                break;
             }
 
-            signal::Event::TrickleIce(trickle) => match &peer {
-                Some(peer) => {
+            signal::Event::TrickleIce(trickle) => match &joined {
+                Some((peer, _)) => {
                     info!("trickle ice: {:#?}", trickle);
-                    peer.trickle_ice_candidate(trickle.target, trickle.candidate.into())
+                    if let Err(err) = peer
+                        .trickle_ice_candidate(trickle.target, trickle.candidate.into())
                         .await
-                        .expect("error adding trickle candidate");
+                    {
+                        error!("error adding trickle candidate: {}", err);
+                    }
                 }
                 None => {
                     error!("peer has not joined session yet");
                 }
             },
 
-            signal::Event::PublisherOffer(res, offer) => match &peer {
-                Some(peer) => {
-                    info!("publisher made offer");
-
-                    let answer = peer
-                        .publisher_get_answer_for_offer(offer.desc)
-                        .await
-                        .expect("publisher error setting remote description");
+            signal::Event::PublisherOffer(res, offer) => {
+                let result = match &joined {
+                    Some((peer, _)) => {
+                        info!("publisher made offer");
+                        peer.set_track_labels(offer.tracks).await;
+                        peer.publisher_get_answer_for_offer(offer.desc)
+                            .await
+                            .map_err(jsonrpc::Error::from)
+                    }
+                    None => Err(jsonrpc::Error::not_joined()),
+                };
+                let _ = res.send(result);
+            }
 
-                    res.send(answer).expect("error sending answer");
+            signal::Event::SubscriberAnswer(answer) => match &joined {
+                Some((peer, _)) => {
+                    info!("subscriber got answer");
+                    if let Err(err) = peer.subscriber_set_answer(answer.desc).await {
+                        error!("subscriber error setting remote description: {}", err);
+                    }
                 }
                 None => {
                     error!("peer has not joined session yet");
                 }
             },
-
-            signal::Event::SubscriberAnswer(answer) => match &peer {
-                Some(peer) => {
-                    info!("subscriber got answer");
-                    peer.subscriber_set_answer(answer.desc)
-                        .await
-                        .expect("subscriber error setting remote description");
+            signal::Event::SelectLayer(layer) => match &joined {
+                Some((peer, _)) => {
+                    info!("subscriber selected layer: {:#?}", layer);
+                    if let Err(err) = peer.select_layer(&layer.track_id, layer.rid).await {
+                        error!("error selecting layer: {}", err);
+                    }
                 }
                 None => {
                     error!("peer has not joined session yet");
                 }
             },
-            signal::Event::Presence(presence) => match &peer {
-                Some(peer) => {
-                    if let Some(ref mut session) = joined_session.as_mut() {
-                        session.presence_set(peer.id, presence.meta).await;
+            signal::Event::Stats(res) => {
+                let result = match &joined {
+                    Some((_, session)) => Ok(signal::StatsResponse {
+                        tracks: session.stats().await,
+                    }),
+                    None => Err(jsonrpc::Error::not_joined()),
+                };
+                let _ = res.send(result);
+            }
+            signal::Event::Subscribe(res, subscribe) => {
+                let result = match &joined {
+                    Some((peer, _)) if !peer.role().await.subscribes() => {
+                        Err(jsonrpc::Error::from(crate::Error::Forbidden(
+                            "peer role may not subscribe".to_owned(),
+                        )))
+                    }
+                    Some((peer, session)) => {
+                        info!("subscribe: {:#?}", subscribe);
+                        session
+                            .subscribe(peer.id, subscribe.track_ids, subscribe.kinds)
+                            .await
+                            .map(|track_ids| signal::SubscribeResponse { track_ids })
+                            .map_err(jsonrpc::Error::from)
                     }
+                    None => Err(jsonrpc::Error::not_joined()),
+                };
+                let _ = res.send(result);
+            }
+            signal::Event::Unsubscribe(res, unsubscribe) => {
+                let result = match &joined {
+                    Some((peer, session)) => {
+                        info!("unsubscribe: {:#?}", unsubscribe);
+                        session
+                            .unsubscribe(peer.id, unsubscribe.track_ids, unsubscribe.kinds)
+                            .await
+                            .map(|track_ids| signal::SubscribeResponse { track_ids })
+                            .map_err(jsonrpc::Error::from)
+                    }
+                    None => Err(jsonrpc::Error::not_joined()),
+                };
+                let _ = res.send(result);
+            }
+            signal::Event::SetRole(res, set_role) => {
+                let result = match &joined {
+                    Some(_) if !permissions.admin => Err(jsonrpc::Error::from(
+                        crate::Error::Forbidden("only moderators may change roles".to_owned()),
+                    )),
+                    Some((_, session)) => {
+                        info!("set role: {:#?}", set_role);
+                        session
+                            .set_role(set_role.peer_id, set_role.role)
+                            .await
+                            .map_err(jsonrpc::Error::from)
+                    }
+                    None => Err(jsonrpc::Error::not_joined()),
+                };
+                let _ = res.send(result);
+            }
+            signal::Event::IceRestart(res, restart) => {
+                let result = match (&joined, restart.target) {
+                    (None, _) => Err(jsonrpc::Error::not_joined()),
+                    (Some((peer, _)), peer::TRANSPORT_TARGET_SUB) => peer
+                        .restart_subscriber_ice()
+                        .await
+                        .map(|_| serde_json::Map::new())
+                        .map_err(jsonrpc::Error::from),
+                    // the client owns the publisher offer, it restarts by offering new credentials
+                    (Some(_), peer::TRANSPORT_TARGET_PUB) => {
+                        let _ = tx.unbounded_send(Ok(signal::Event::ClientIceRestart(restart)));
+                        Ok(serde_json::Map::new())
+                    }
+                    (Some(_), target) => Err(jsonrpc::Error::invalid_params(format!(
+                        "unknown ice restart target {}",
+                        target
+                    ))),
+                };
+                let _ = res.send(result);
+            }
+            signal::Event::Presence(presence) => match &joined {
+                Some((peer, session)) => {
+                    session.presence_set(peer.id, presence.meta).await;
                 }
                 None => {
                     error!("peer has not joined session yet");
@@ -154,14 +973,318 @@ pub async fn event_loop<C, S>(
 
     info!("signal event loop finished");
 
-    if let Some(session) = joined_session {
-        if let Some(peer) = peer {
-            session
-                .remove_peer(peer.id)
-                .await
-                .expect("error removing peer");
+    if let Some((peer, session)) = joined {
+        let park = !left && !server.shutting_down();
+        match resume_token {
+            Some(token) => {
+                if !server.release(&token, park).await {
+                    info!("peer id={} moved to another connection", peer.id);
+                }
+            }
+            None => server.leave(&peer, &session).await,
+        }
+    }
+}
+
+/// Creates a Peer for the join request, answers its offer and adds it to the session
+async fn join_session<C, S>(
+    coordinator: &C,
+    mut peer_config: peer::PeerConfig,
+    ice_servers: Option<&IceServers>,
+    tx: &signal::WriteStream,
+    join: signal::JoinMsg,
+) -> std::result::Result<
+    (
+        Arc<peer::Peer>,
+        session::SessionHandle<S>,
+        signal::JoinResponse,
+    ),
+    jsonrpc::Error,
+>
+where
+    C: Coordinator<S>,
+    S: Session,
+{
+    let session = coordinator.get_or_create_session(join.sid).await?;
+
+    // Token claims take precedence over what the client sent, the role is limited to what
+    // the token permits
+    let (identity, role) = match join.claims {
+        Some(claims) => (
+            claims.identity.or(join.identity),
+            peer::Role::new(
+                join.role.publishes() && claims.permissions.publish,
+                join.role.subscribes() && claims.permissions.subscribe,
+            ),
+        ),
+        None => (join.identity, Some(join.role)),
+    };
 
+    // TURN credentials are issued for the identity when it's a string or number
+    let mut client_ice_servers = vec![];
+    if let Some(ice_servers) = ice_servers {
+        let credential_identity = match &identity {
+            Some(serde_json::Value::String(identity)) => Some(identity.clone()),
+            Some(identity @ serde_json::Value::Number(_)) => Some(identity.to_string()),
+            _ => None,
+        };
+        let (servers, client_servers) = ice_servers.issue(credential_identity.as_deref());
+        peer_config.rtc_config.ice_servers = servers;
+        client_ice_servers = client_servers;
+    }
+
+    let result = async {
+        let role = role.ok_or_else(|| {
+            crate::Error::Forbidden("token permits neither publish nor subscribe".to_owned())
+        })?;
+        let p = peer::Peer::new(
+            tx.clone(),
+            session.write_channel(),
+            peer::PeerConfig {
+                loopback: join.loopback,
+                auto_subscribe: join.auto_subscribe,
+                identity,
+                role,
+                ..peer_config
+            },
+        )
+        .await
+        .map_err(jsonrpc::Error::from)?;
+
+        // viewers have no publisher connection, their offers are rejected
+        p.set_track_labels(join.tracks).await;
+        let (answer, offer_error) = match join.offer {
+            Some(offer) => match p.publisher_get_answer_for_offer(offer).await {
+                Ok(answer) => (Some(answer), None),
+                // the role doesn't publish, only the offer is rejected
+                Err(err @ crate::Error::Forbidden(_)) => {
+                    info!("peer id={} offered tracks it may not publish", p.id);
+                    (None, Some(err.into()))
+                }
+                Err(err) => {
+                    let _ = p.close().await;
+                    return Err(err.into());
+                }
+            },
+            None => (None, None),
+        };
+
+        info!("answer created ");
+        if let Err(err) = session.add_peer(p.id, p.clone()).await {
+            let _ = p.close().await;
+            return Err(err.into());
+        }
+
+        Ok((p, answer, offer_error))
+    }
+    .await;
+
+    match result {
+        Ok((p, answer, offer_error)) => {
+            let response = signal::JoinResponse {
+                answer,
+                peer_id: p.id,
+                role: p.role().await,
+                participants: session.participants().await,
+                resume_token: None,
+                ice_servers: client_ice_servers,
+                offer_error,
+            };
+            Ok((p, session, response))
+        }
+        Err(err) => {
+            // don't leave an empty session behind
             coordinator.cleanup_session(session.id()).await;
+            Err(err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_channel::mpsc;
+    use tokio::io::AsyncReadExt;
+    use webrtc::api::media_engine::MediaEngine;
+    use webrtc::api::APIBuilder;
+    use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+    use webrtc::rtp_transceiver::rtp_codec::RTPCodecType;
+
+    async fn offer() -> RTCSessionDescription {
+        let mut media_engine = MediaEngine::default();
+        media_engine.register_default_codecs().unwrap();
+        let api = APIBuilder::new().with_media_engine(media_engine).build();
+        let pc = api.new_peer_connection(Default::default()).await.unwrap();
+        pc.add_transceiver_from_kind(RTPCodecType::Audio, None)
+            .await
+            .unwrap();
+
+        let offer = pc.create_offer(None).await.unwrap();
+        pc.close().await.unwrap();
+        offer
+    }
+
+    const SUBSCRIBE_ONLY: Permissions = Permissions {
+        publish: false,
+        subscribe: true,
+        admin: false,
+    };
+
+    /// Join request carrying the verified claims of a token for the session
+    fn join_with(sid: &str, permissions: Permissions) -> signal::JoinMsg {
+        signal::JoinMsg {
+            claims: Some(auth::Claims {
+                sid: sid.to_owned(),
+                identity: None,
+                exp: u64::MAX,
+                permissions,
+            }),
+            ..serde_json::from_value(serde_json::json!({ "sid": sid })).unwrap()
+        }
+    }
+
+    fn viewer_joins() -> Vec<signal::JoinMsg> {
+        let viewer = signal::JoinMsg {
+            role: peer::Role::Viewer,
+            ..serde_json::from_value(serde_json::json!({"sid": "room"})).unwrap()
+        };
+        // a participant whose token only permits subscribing
+        vec![viewer, join_with("room", SUBSCRIBE_ONLY)]
+    }
+
+    type LocalServer = Arc<Server<LocalCoordinator<LocalSession>, LocalSession>>;
+
+    async fn local_server() -> LocalServer {
+        SwitchboardServer::builder().start().await.unwrap().server
+    }
+
+    /// Runs the event loop of a connection, returning the sender of its client events and
+    /// the receiver of what it sends back
+    fn connect(server: &LocalServer) -> (signal::WriteStream, signal::ReadStream) {
+        let (events_tx, events_rx) = mpsc::unbounded();
+        let (tx, rx) = mpsc::unbounded();
+        tokio::spawn(enc!( (server) async move {
+            event_loop(&server, events_rx, tx).await;
+        }));
+        (events_tx, rx)
+    }
+
+    async fn request<T>(
+        events: &signal::WriteStream,
+        event: impl FnOnce(signal::Reply<T>) -> signal::Event,
+    ) -> std::result::Result<T, jsonrpc::Error> {
+        let (res, rx) = oneshot::channel();
+        events.unbounded_send(Ok(event(res))).unwrap();
+        rx.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn viewer_offer_is_rejected_without_failing_the_join() {
+        for mut join in viewer_joins() {
+            let coordinator = LocalCoordinator::<LocalSession>::new(CoordinatorConfig::default());
+            let (tx, _rx) = mpsc::unbounded();
+            join.offer = Some(offer().await);
+
+            let (peer, session, response) =
+                join_session(&*coordinator, peer::PeerConfig::default(), None, &tx, join)
+                    .await
+                    .unwrap();
+
+            assert_eq!(response.role, peer::Role::Viewer);
+            assert!(response.answer.is_none());
+            assert_eq!(response.offer_error.unwrap().code, jsonrpc::FORBIDDEN);
+            assert_eq!(session.participants().await.len(), 1);
+
+            session.remove_peer(peer.id).await.unwrap();
         }
     }
+
+    #[tokio::test]
+    async fn failed_join_keeps_the_joined_permissions() {
+        let server = local_server().await;
+        let (events, _rx) = connect(&server);
+
+        let joined = request(&events, |res| {
+            signal::Event::JoinRequest(res, join_with("a", SUBSCRIBE_ONLY))
+        })
+        .await
+        .unwrap();
+        let err = request(&events, |res| {
+            signal::Event::JoinRequest(res, join_with("b", Permissions::all()))
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(err.code, jsonrpc::ALREADY_JOINED);
+
+        let set_role = signal::SetRoleMsg {
+            peer_id: joined.peer_id,
+            role: peer::Role::Viewer,
+        };
+        let err = request(&events, |res| signal::Event::SetRole(res, set_role))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, jsonrpc::FORBIDDEN);
+
+        assert_eq!(server.coordinator.session_count().await, 1);
+    }
+
+    #[tokio::test]
+    async fn publisher_ice_restart_asks_the_client_to_reoffer() {
+        let server = local_server().await;
+        let (events, mut rx) = connect(&server);
+
+        request(&events, |res| {
+            signal::Event::JoinRequest(res, join_with("a", Permissions::all()))
+        })
+        .await
+        .unwrap();
+        let restart = signal::IceRestartMsg {
+            target: peer::TRANSPORT_TARGET_PUB,
+        };
+        request(&events, |res| signal::Event::IceRestart(res, restart))
+            .await
+            .unwrap();
+
+        loop {
+            match rx.next().await {
+                Some(Ok(signal::Event::ClientIceRestart(restart))) => {
+                    assert_eq!(restart.target, peer::TRANSPORT_TARGET_PUB);
+                    break;
+                }
+                Some(_) => continue,
+                None => panic!("no ice_restart notification"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn draining_server_refuses_joins_and_connections() {
+        let switchboard = SwitchboardServer::builder().start().await.unwrap();
+        let server = switchboard.server.clone();
+        server.shutdown.send_replace(ShutdownState::Draining);
+
+        let (events, _rx) = connect(&server);
+        let err = request(&events, |res| {
+            signal::Event::JoinRequest(res, join_with("a", Permissions::all()))
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(err.code, jsonrpc::SHUTTING_DOWN);
+        let resume = signal::ResumeMsg {
+            token: "token".to_owned(),
+            ice_restart: false,
+        };
+        let err = request(&events, |res| signal::Event::Resume(res, resume))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, jsonrpc::SHUTTING_DOWN);
+        assert_eq!(server.coordinator.session_count().await, 0);
+
+        let (io, mut client) = tokio::io::duplex(64);
+        tokio::time::timeout(Duration::from_secs(1), switchboard.serve_upgraded(io, None))
+            .await
+            .unwrap();
+        let mut buf = [0u8; 1];
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+    }
 }
@@ -1,26 +1,134 @@
-use anyhow::Result;
 use enclose::enc;
 use futures_channel::{mpsc, oneshot};
 use futures_util::StreamExt;
 use log::*;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
+use webrtc::ice_transport::ice_server::RTCIceServer;
 use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
 
+use super::auth::{Claims, ConnectionAuth};
 use super::jsonrpc;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Mutex, PoisonError};
+
+use crate::sfu::peer;
+use crate::sfu::routing::{MediaTrackRouterStats, TrackLabels};
+use crate::{Error, Result};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct JoinMsg {
     pub sid: String,
-    pub offer: RTCSessionDescription,
+    /// Publisher offer, viewers join without one
+    #[serde(default)]
+    pub offer: Option<RTCSessionDescription>,
+    /// Requested role, limited by the permissions of the join token
+    #[serde(default)]
+    pub role: peer::Role,
+    /// Receive this peer's own published tracks back (echo test pages)
+    #[serde(default)]
+    pub loopback: bool,
+    /// Subscribe to every published track, otherwise tracks are picked with subscribe
+    #[serde(default = "default_auto_subscribe")]
+    pub auto_subscribe: bool,
+    /// Labels for the tracks in the offer, keyed by track id
+    #[serde(default)]
+    pub tracks: HashMap<String, TrackLabels>,
+    /// App-defined identity of the participant, shared with the other peers
+    #[serde(default)]
+    pub identity: Option<Value>,
+    /// Signed join token, required when authentication is enabled (can also be passed as the
+    /// token query parameter of the websocket url)
+    #[serde(default)]
+    pub token: Option<String>,
+    /// Claims of the verified token
+    #[serde(skip)]
+    pub claims: Option<Claims>,
+}
+
+fn default_auto_subscribe() -> bool {
+    true
+}
+
+/// The publisher answer (flattened so clients reading only the answer keep working, missing
+/// without an offer), this peer's id and role and everyone in the session (including this peer)
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JoinResponse {
+    #[serde(flatten)]
+    pub answer: Option<RTCSessionDescription>,
+    pub peer_id: peer::Id,
+    pub role: peer::Role,
+    pub participants: Vec<Participant>,
+    /// Token to resume this peer with on a new connection if this one drops, missing when
+    /// resumption is disabled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resume_token: Option<String>,
+    /// STUN/TURN servers for the client's peer connections (eg. the embedded TURN server)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ice_servers: Vec<RTCIceServer>,
+    /// Why the offer got no answer, eg. a viewer's offered tracks are rejected while its join
+    /// succeeds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offer_error: Option<jsonrpc::Error>,
 }
 
-pub type JoinResponse = RTCSessionDescription;
+/// Takes over a peer whose connection dropped, instead of joining again
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResumeMsg {
+    pub token: String,
+    /// Restart ICE on the subscriber connection with a new offer, the client restarts the
+    /// publisher connection by sending an offer with new ICE credentials
+    #[serde(default)]
+    pub ice_restart: bool,
+}
+
+/// Like JoinResponse without a publisher answer, the resume token is replaced by a new one
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResumeResponse {
+    pub peer_id: peer::Id,
+    pub role: peer::Role,
+    pub participants: Vec<Participant>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resume_token: Option<String>,
+}
+
+/// A peer of the session, also sent to every peer when its role changes
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Participant {
+    pub peer_id: peer::Id,
+    pub identity: Option<Value>,
+    pub role: peer::Role,
+}
+
+/// Sent to every connection when the server starts shutting down, clients should leave and
+/// reconnect after reconnect_after_ms, before the remaining peers are closed after
+/// drain_timeout_ms
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ServerShutdownNotification {
+    pub drain_timeout_ms: u64,
+    pub reconnect_after_ms: u64,
+}
+
+/// Changes the role of a peer, only moderators (admin permission) may send it
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SetRoleMsg {
+    pub peer_id: peer::Id,
+    pub role: peer::Role,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PeerLeftNotification {
+    pub peer_id: peer::Id,
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct NegotiateMsg {
     pub desc: RTCSessionDescription,
+    /// Labels for the tracks in a publisher offer, keyed by track id
+    #[serde(default)]
+    pub tracks: HashMap<String, TrackLabels>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -43,7 +151,7 @@ impl From<RTCIceCandidateInit> for TrickleCandidate {
         TrickleCandidate {
             candidate: t.candidate,
             sdp_mid: t.sdp_mid,
-            sdp_mline_index: t.sdp_mline_index.unwrap() as u32,
+            sdp_mline_index: t.sdp_mline_index.unwrap_or(0) as u32,
         }
     }
 }
@@ -59,28 +167,176 @@ impl From<TrickleCandidate> for RTCIceCandidateInit {
     }
 }
 
+/// Asks for an ICE restart of the subscriber (target 1), which gets a new offer
+/// The client owns the publisher offer, for target 0 the server sends back an ice_restart
+/// notification with the same target and the client re-offers with iceRestart: true
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IceRestartMsg {
+    pub target: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SelectLayerMsg {
+    pub track_id: String,
+    pub rid: Option<String>,
+}
+
+/// Tracks to (un)subscribe by id and/or by kind ("audio"/"video")
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SubscribeMsg {
+    #[serde(default)]
+    pub track_ids: Vec<String>,
+    #[serde(default)]
+    pub kinds: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SubscribeResponse {
+    /// Every track the peer is subscribed to after the change
+    pub track_ids: Vec<String>,
+}
+
+/// Sent to every peer in the session when a track is published (and again when a simulcast
+/// layer is added to it)
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TrackInfo {
+    pub peer_id: peer::Id,
+    /// Ids the subscriber connection uses for the track (unique within the session)
+    pub track_id: String,
+    pub stream_id: String,
+    /// Ids the publisher used for the track in its offer
+    pub publisher_track_id: String,
+    pub publisher_stream_id: String,
+    pub kind: String,
+    pub codec: String,
+    /// Simulcast rids, empty if the track isn't simulcast
+    pub layers: Vec<String>,
+    pub labels: TrackLabels,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TrackUnpublishedNotification {
+    pub peer_id: peer::Id,
+    pub track_id: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct StatsResponse {
+    pub tracks: Vec<MediaTrackRouterStats>,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Presence {
     pub revision: u64,
     pub meta: serde_json::Value,
 }
 
+/// Reply to a request, errors are sent back as json-rpc error objects
+pub type Reply<T> = oneshot::Sender<std::result::Result<T, jsonrpc::Error>>;
+
 pub enum Event {
-    JoinRequest(oneshot::Sender<JoinResponse>, JoinMsg),
-    PublisherOffer(oneshot::Sender<RTCSessionDescription>, NegotiateMsg),
+    JoinRequest(Reply<JoinResponse>, JoinMsg),
+    PublisherOffer(Reply<RTCSessionDescription>, NegotiateMsg),
     SubscriberOffer(RTCSessionDescription),
     SubscriberAnswer(NegotiateMsg),
     TrickleIce(TrickleNotification),
     Presence(Presence),
+    PeerJoined(Participant),
+    PeerLeft(PeerLeftNotification),
+    TrackPublished(TrackInfo),
+    TrackUnpublished(TrackUnpublishedNotification),
+    SelectLayer(SelectLayerMsg),
+    Stats(Reply<StatsResponse>),
+    Subscribe(Reply<SubscribeResponse>, SubscribeMsg),
+    Unsubscribe(Reply<SubscribeResponse>, SubscribeMsg),
+    SetRole(Reply<Participant>, SetRoleMsg),
+    Resume(Reply<ResumeResponse>, ResumeMsg),
+    IceRestart(Reply<serde_json::Map<String, Value>>, IceRestartMsg),
+    /// Asks the client to restart the ICE of its publisher with a new offer
+    ClientIceRestart(IceRestartMsg),
+    /// Leaves the session right away, a dropped connection can be resumed for a while
+    Leave,
+    RoleChanged(Participant),
+    ServerShutdown(ServerShutdownNotification),
+    /// Closes the connection once the events before it are sent
+    Close,
 }
 
 pub type ReadStream = mpsc::UnboundedReceiver<Result<Event>>;
 pub type WriteStream = mpsc::UnboundedSender<Result<Event>>;
 
+/// Events kept for a detached peer, the oldest are dropped past this
+const MAX_QUEUED_EVENTS: usize = 1024;
+
+/// Signal connection of a joined peer, swapped when the peer resumes on a new connection
+/// Events sent while no connection is attached are queued until one is
+pub struct PeerSignal {
+    state: Mutex<PeerSignalState>,
+}
+
+enum PeerSignalState {
+    Attached(WriteStream),
+    Detached(VecDeque<Event>),
+}
+
+impl PeerSignal {
+    pub fn new(tx: WriteStream) -> PeerSignal {
+        PeerSignal {
+            state: Mutex::new(PeerSignalState::Attached(tx)),
+        }
+    }
+
+    /// Sends the event to the attached connection, or queues it
+    pub fn send(&self, mut evt: Event) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if let PeerSignalState::Attached(tx) = &*state {
+            match tx.unbounded_send(Ok(evt)) {
+                Ok(()) => return,
+                // the connection closed before the peer was detached, keep the event for the
+                // next one
+                Err(err) => match err.into_inner() {
+                    Ok(unsent) => evt = unsent,
+                    Err(_) => return,
+                },
+            }
+            debug!("signal connection closed, queueing events");
+            *state = PeerSignalState::Detached(VecDeque::new());
+        }
+
+        if let PeerSignalState::Detached(queue) = &mut *state {
+            if queue.len() >= MAX_QUEUED_EVENTS {
+                queue.pop_front();
+            }
+            queue.push_back(evt);
+        }
+    }
+
+    /// Attaches a new connection, sending it the queued events first
+    pub fn attach(&self, tx: WriteStream) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if let PeerSignalState::Detached(queue) = &mut *state {
+            debug!("flushing {} queued signal events", queue.len());
+            for evt in queue.drain(..) {
+                let _ = tx.unbounded_send(Ok(evt));
+            }
+        }
+        *state = PeerSignalState::Attached(tx);
+    }
+
+    /// Queues events until the next attach
+    pub fn detach(&self) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if let PeerSignalState::Attached(_) = &*state {
+            *state = PeerSignalState::Detached(VecDeque::new());
+        }
+    }
+}
+
 /// Process json::Event streams into signal::Event streams
 pub async fn handle_messages(
     mut rpc_read: jsonrpc::ReadStream,
     rpc_write: jsonrpc::WriteStream,
+    auth: Option<ConnectionAuth>,
 ) -> (ReadStream, WriteStream) {
     let (sig_read_tx, sig_read_rx) = mpsc::unbounded::<Result<Event>>();
     let (sig_write_tx, mut sig_write_rx) = mpsc::unbounded::<Result<Event>>();
@@ -88,76 +344,27 @@ pub async fn handle_messages(
     tokio::spawn(enc!( (rpc_write) async move {
         while let Some(Ok(rpc)) = rpc_read.next().await {
             match rpc {
-                jsonrpc::Event::Request(r) => match r.method.as_str() {
-                    "join" => {
-                        let id = r.id;
-                        let (tx, rx) = oneshot::channel::<JoinResponse>();
-
-                        info!("got join request");
+                jsonrpc::Event::Request(r) => {
+                    let id = r.id.clone();
+                    if let Err(err) = handle_request(r, &rpc_write, &sig_read_tx, auth.as_ref()) {
+                        warn!("error handling request: {}", err);
+                        let unauthorized = err.code == jsonrpc::UNAUTHORIZED;
+                        let response = jsonrpc::Response::err(id, err);
+                        let _ = rpc_write.unbounded_send(Ok(jsonrpc::Event::Response(response)));
 
-                        tokio::spawn(enc!( (rpc_write) async move {
-                            let result = rx.await.unwrap();
-                            let response = jsonrpc::Response{
-                                id: id,
-                                result: Some(serde_json::from_value(serde_json::to_value(result).unwrap()).expect("error creating response")),
-                                error: None
-                            };
-
-                            rpc_write.unbounded_send(Ok(jsonrpc::Event::Response(response))).expect("error sending response");
-                        }));
-
-                        let j: JoinMsg =
-                            serde_json::from_value(Value::Object(r.params)).expect("error parsing");
-
-                        sig_read_tx.unbounded_send(Ok(Event::JoinRequest(tx, j))).expect("error forwarding signal message");
-                    }
-                    "offer" => {
-                        let id = r.id;
-                        let (tx, rx) = oneshot::channel::<RTCSessionDescription>();
-
-                        info!("got publisher negotiation offer");
-
-                        tokio::spawn(enc!( (rpc_write) async move {
-                            let result = rx.await.unwrap();
-                            let response = jsonrpc::Response{
-                                id: id,
-                                result: Some(serde_json::from_value(serde_json::to_value(result).unwrap()).expect("error creating response")),
-                                error: None
-                            };
-
-                            rpc_write.unbounded_send(Ok(jsonrpc::Event::Response(response))).expect("error sending response");
-                        }));
-
-                        let o: NegotiateMsg =
-                            serde_json::from_value(Value::Object(r.params)).expect("error parsing");
-
-                        sig_read_tx.unbounded_send(Ok(Event::PublisherOffer(tx, o))).expect("error forwarding signal message");
-                    },
-                    "presence_set" => {
-                        let meta: serde_json::Value =
-                            serde_json::from_value(Value::Object(r.params)).expect("error parsing");
-                        sig_read_tx.unbounded_send(Ok(Event::Presence(Presence{
-                            revision: 0,
-                            meta: meta,
-                        }))).expect("error forwarding signal message");
+                        // Connections failing authentication are closed
+                        if unauthorized {
+                            let _ = rpc_write.unbounded_send(Ok(jsonrpc::Event::Close));
+                            break;
+                        }
                     }
-
-                    _ => {}
-                },
-                jsonrpc::Event::Notification(n) => match n.method.as_str() {
-                    "trickle" => {
-                        info!("got trickle notification");
-                        let t: TrickleNotification
-                            = serde_json::from_value(Value::Object(n.params)).expect("error parsing");
-                        sig_read_tx.unbounded_send(Ok(Event::TrickleIce(t))).expect("error forwarding signal message");
-                    },
-                    "answer" => {
-                        let n: NegotiateMsg =
-                            serde_json::from_value(Value::Object(n.params)).expect("error parsing");
-                        sig_read_tx.unbounded_send(Ok(Event::SubscriberAnswer(n))).expect("error forwarding signal message");
+                }
+                jsonrpc::Event::Notification(n) => {
+                    // Notifications have no response, errors can only be logged
+                    if let Err(err) = handle_notification(n, &sig_read_tx) {
+                        warn!("error handling notification: {}", err);
                     }
-                    _ => {}
-                },
+                }
                 _ => {}
             }
         }
@@ -165,32 +372,306 @@ pub async fn handle_messages(
 
     tokio::spawn(enc!( (rpc_write) async move {
         while let Some(Ok(evt)) = sig_write_rx.next().await {
-            match evt {
-                Event::TrickleIce(ice) => {
-                    let n = jsonrpc::Notification{
-                        method: "trickle".to_owned(),
-                        params: serde_json::from_value(serde_json::to_value(ice).unwrap()).unwrap(),
-                    };
-                    rpc_write.unbounded_send(Ok(jsonrpc::Event::Notification(n))).expect("error sending notification");
-                }
-                Event::SubscriberOffer(offer) => {
-                    let n = jsonrpc::Notification{
-                        method: "offer".to_owned(),
-                        params: serde_json::from_value(serde_json::to_value(offer).unwrap()).unwrap(),
-                    };
-                    rpc_write.unbounded_send(Ok(jsonrpc::Event::Notification(n))).expect("error sending notification");
+            let n = match evt {
+                Event::TrickleIce(ice) => notification("trickle", ice),
+                Event::SubscriberOffer(offer) => notification("offer", offer),
+                Event::Presence(presence) => notification("presence", presence),
+                Event::PeerJoined(participant) => notification("peer_joined", participant),
+                Event::PeerLeft(left) => notification("peer_left", left),
+                Event::TrackPublished(info) => notification("track_published", info),
+                Event::TrackUnpublished(info) => notification("track_unpublished", info),
+                Event::RoleChanged(participant) => notification("role_changed", participant),
+                Event::ServerShutdown(shutdown) => notification("server_shutdown", shutdown),
+                Event::ClientIceRestart(restart) => notification("ice_restart", restart),
+                Event::Close => {
+                    let _ = rpc_write.unbounded_send(Ok(jsonrpc::Event::Close));
+                    break;
                 }
-                Event::Presence(presence) => {
-                    let n = jsonrpc::Notification {
-                        method: "presence".to_owned(),
-                        params: serde_json::from_value(serde_json::to_value(presence).unwrap()).unwrap(),
-                    };
-                    rpc_write.unbounded_send(Ok(jsonrpc::Event::Notification(n))).expect("error sending notification");
+                _ => continue,
+            };
+
+            let n = match n {
+                Ok(n) => n,
+                Err(err) => {
+                    error!("error creating notification: {}", err);
+                    continue;
                 }
-                _ => {}
+            };
+            if rpc_write.unbounded_send(Ok(jsonrpc::Event::Notification(n))).is_err() {
+                debug!("json-rpc connection closed");
+                break;
             }
         }
     }));
 
     (sig_read_rx, sig_write_tx)
 }
+
+/// Turns a json-rpc request into a signal::Event, the response is sent once the Reply is used
+fn handle_request(
+    r: jsonrpc::Request,
+    rpc_write: &jsonrpc::WriteStream,
+    sig_read_tx: &WriteStream,
+    auth: Option<&ConnectionAuth>,
+) -> std::result::Result<(), jsonrpc::Error> {
+    info!("got {} request", r.method);
+
+    // params are parsed before creating the reply so a failure results in a single response
+    let evt = match r.method.as_str() {
+        "join" => {
+            let mut join: JoinMsg = parse_params(r.params)?;
+            if let Some(auth) = auth {
+                let token = join.token.as_deref().or(auth.upgrade_token.as_deref());
+                join.claims = Some(auth.verifier.authorize_join(token, &join.sid)?);
+            }
+            Event::JoinRequest(reply(rpc_write, r.id), join)
+        }
+        "offer" => {
+            let offer = parse_params(r.params)?;
+            Event::PublisherOffer(reply(rpc_write, r.id), offer)
+        }
+        "stats" => Event::Stats(reply(rpc_write, r.id)),
+        "subscribe" => {
+            let subscribe = parse_params(r.params)?;
+            Event::Subscribe(reply(rpc_write, r.id), subscribe)
+        }
+        "unsubscribe" => {
+            let unsubscribe = parse_params(r.params)?;
+            Event::Unsubscribe(reply(rpc_write, r.id), unsubscribe)
+        }
+        "set_role" => {
+            let set_role = parse_params(r.params)?;
+            Event::SetRole(reply(rpc_write, r.id), set_role)
+        }
+        "ice_restart" => {
+            let restart = parse_params(r.params)?;
+            Event::IceRestart(reply(rpc_write, r.id), restart)
+        }
+        // the resume token stands in for the join token
+        "resume" => {
+            let resume = parse_params(r.params)?;
+            Event::Resume(reply(rpc_write, r.id), resume)
+        }
+        "presence_set" => {
+            let meta = Value::Object(r.params);
+            let response = jsonrpc::Response::ok(r.id, serde_json::Map::new());
+            rpc_write
+                .unbounded_send(Ok(jsonrpc::Event::Response(response)))
+                .map_err(jsonrpc::Error::internal)?;
+            Event::Presence(Presence { revision: 0, meta })
+        }
+        method => return Err(jsonrpc::Error::method_not_found(method)),
+    };
+
+    sig_read_tx
+        .unbounded_send(Ok(evt))
+        .map_err(jsonrpc::Error::internal)
+}
+
+/// Turns a json-rpc notification into a signal::Event
+fn handle_notification(
+    n: jsonrpc::Notification,
+    sig_read_tx: &WriteStream,
+) -> std::result::Result<(), jsonrpc::Error> {
+    info!("got {} notification", n.method);
+
+    let evt = match n.method.as_str() {
+        "trickle" => Event::TrickleIce(parse_params(n.params)?),
+        "answer" => Event::SubscriberAnswer(parse_params(n.params)?),
+        "select_layer" => Event::SelectLayer(parse_params(n.params)?),
+        "leave" => Event::Leave,
+        method => return Err(jsonrpc::Error::method_not_found(method)),
+    };
+
+    sig_read_tx
+        .unbounded_send(Ok(evt))
+        .map_err(jsonrpc::Error::internal)
+}
+
+fn parse_params<T: DeserializeOwned>(
+    params: serde_json::Map<String, Value>,
+) -> std::result::Result<T, jsonrpc::Error> {
+    serde_json::from_value(Value::Object(params)).map_err(jsonrpc::Error::invalid_params)
+}
+
+/// Creates a Reply that sends the json-rpc response for request id once it is used
+/// A reply dropped without an answer is reported as an internal error
+fn reply<T: Serialize + Send + 'static>(
+    rpc_write: &jsonrpc::WriteStream,
+    id: jsonrpc::Id,
+) -> Reply<T> {
+    let (tx, rx) = oneshot::channel::<std::result::Result<T, jsonrpc::Error>>();
+
+    tokio::spawn(enc!( (rpc_write) async move {
+        let result = rx
+            .await
+            .unwrap_or_else(|_| Err(jsonrpc::Error::internal("request was dropped")))
+            .and_then(|result| match serde_json::to_value(result) {
+                Ok(Value::Object(map)) => Ok(map),
+                Ok(_) => Err(jsonrpc::Error::internal("response is not an object")),
+                Err(err) => Err(jsonrpc::Error::internal(err)),
+            });
+
+        let response = match result {
+            Ok(result) => jsonrpc::Response::ok(id, result),
+            Err(err) => {
+                warn!("request failed: {}", err);
+                jsonrpc::Response::err(id, err)
+            }
+        };
+
+        if rpc_write.unbounded_send(Ok(jsonrpc::Event::Response(response))).is_err() {
+            debug!("connection closed before response was sent");
+        }
+    }));
+
+    tx
+}
+
+fn notification<T: Serialize>(method: &str, params: T) -> Result<jsonrpc::Notification> {
+    match serde_json::to_value(params) {
+        Ok(Value::Object(params)) => Ok(jsonrpc::Notification {
+            method: method.to_owned(),
+            params,
+        }),
+        Ok(_) => Err(Error::Signaling(format!(
+            "{} params are not an object",
+            method
+        ))),
+        Err(err) => Err(Error::Signaling(err.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn request(method: &str, params: Value) -> jsonrpc::Request {
+        jsonrpc::Request {
+            id: jsonrpc::Id::Int(1),
+            method: method.to_owned(),
+            params: params.as_object().cloned().unwrap_or_default(),
+            result: None,
+        }
+    }
+
+    fn request_error(method: &str, params: Value) -> i64 {
+        let (rpc_write, _rpc_rx) = mpsc::unbounded();
+        let (sig_read_tx, _sig_rx) = mpsc::unbounded();
+        handle_request(request(method, params), &rpc_write, &sig_read_tx, None)
+            .unwrap_err()
+            .code
+    }
+
+    #[test]
+    fn unknown_methods_are_not_found() {
+        assert_eq!(
+            request_error("teleport", json!({})),
+            jsonrpc::METHOD_NOT_FOUND
+        );
+
+        let (sig_read_tx, _sig_rx) = mpsc::unbounded();
+        let notification = jsonrpc::Notification {
+            method: "teleport".to_owned(),
+            params: Default::default(),
+        };
+        assert_eq!(
+            handle_notification(notification, &sig_read_tx)
+                .unwrap_err()
+                .code,
+            jsonrpc::METHOD_NOT_FOUND
+        );
+    }
+
+    #[test]
+    fn bad_params_are_invalid() {
+        assert_eq!(request_error("join", json!({})), jsonrpc::INVALID_PARAMS);
+        assert_eq!(
+            request_error("join", json!({"sid": 5})),
+            jsonrpc::INVALID_PARAMS
+        );
+        assert_eq!(
+            request_error("ice_restart", json!({"target": "sub"})),
+            jsonrpc::INVALID_PARAMS
+        );
+
+        let (sig_read_tx, _sig_rx) = mpsc::unbounded();
+        let notification = jsonrpc::Notification {
+            method: "trickle".to_owned(),
+            params: Default::default(),
+        };
+        assert_eq!(
+            handle_notification(notification, &sig_read_tx)
+                .unwrap_err()
+                .code,
+            jsonrpc::INVALID_PARAMS
+        );
+    }
+
+    fn peer_left(n: u128) -> Event {
+        Event::PeerLeft(PeerLeftNotification {
+            peer_id: peer::Id::from_u128(n),
+        })
+    }
+
+    fn received(rx: &mut ReadStream) -> Vec<u128> {
+        let mut ids = vec![];
+        while let Ok(Some(evt)) = rx.try_next() {
+            match evt {
+                Ok(Event::PeerLeft(left)) => ids.push(left.peer_id.as_u128()),
+                _ => panic!("unexpected event"),
+            }
+        }
+        ids
+    }
+
+    #[test]
+    fn delivers_queued_events_in_order_on_attach() {
+        let (tx, mut rx) = mpsc::unbounded();
+        let signal = PeerSignal::new(tx);
+
+        signal.send(peer_left(1));
+        signal.detach();
+        signal.send(peer_left(2));
+        signal.send(peer_left(3));
+        assert_eq!(received(&mut rx), vec![1]);
+
+        let (tx, mut rx) = mpsc::unbounded();
+        signal.attach(tx);
+        signal.send(peer_left(4));
+        assert_eq!(received(&mut rx), vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn queues_events_once_the_connection_closes() {
+        let (tx, rx) = mpsc::unbounded();
+        let signal = PeerSignal::new(tx);
+        drop(rx);
+
+        signal.send(peer_left(1));
+        signal.send(peer_left(2));
+
+        let (tx, mut rx) = mpsc::unbounded();
+        signal.attach(tx);
+        assert_eq!(received(&mut rx), vec![1, 2]);
+    }
+
+    #[test]
+    fn drops_the_oldest_events_past_the_cap() {
+        let (tx, _rx) = mpsc::unbounded();
+        let signal = PeerSignal::new(tx);
+        signal.detach();
+
+        let sent = MAX_QUEUED_EVENTS as u128 + 10;
+        for n in 0..sent {
+            signal.send(peer_left(n));
+        }
+
+        let (tx, mut rx) = mpsc::unbounded();
+        signal.attach(tx);
+        let ids = received(&mut rx);
+        assert_eq!(ids.len(), MAX_QUEUED_EVENTS);
+        assert_eq!(ids, (10..sent).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,177 @@
+use log::*;
+use serde::Deserialize;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock, Weak};
+use std::time::{Duration, SystemTime};
+
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::{self, crypto::ring};
+use tokio_rustls::TlsAcceptor;
+
+use crate::{Error, Result};
+
+/// How often the certificate files are checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// PEM encoded certificate chain and private key to serve wss:// with
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+impl TlsConfig {
+    pub fn new(cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> TlsConfig {
+        TlsConfig {
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+        }
+    }
+
+    /// Loads the certificate and returns an acceptor serving it
+    /// The certificate is reloaded on SIGHUP or when either file changes, for as long as the
+    /// acceptor is alive
+    pub fn acceptor(&self) -> Result<TlsAcceptor> {
+        let resolver = Arc::new(CertResolver {
+            current: RwLock::new(Arc::new(self.load()?)),
+        });
+        tokio::spawn(watch(self.clone(), Arc::downgrade(&resolver)));
+
+        let config =
+            rustls::ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .map_err(|err| Error::Tls(err.to_string()))?
+                .with_no_client_auth()
+                .with_cert_resolver(resolver);
+
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+
+    /// Reads the certificate chain and private key
+    fn load(&self) -> Result<CertifiedKey> {
+        let mut reader = BufReader::new(File::open(&self.cert_path)?);
+        let certs = rustls_pemfile::certs(&mut reader).collect::<std::io::Result<Vec<_>>>()?;
+        if certs.is_empty() {
+            return Err(Error::Tls(format!(
+                "no certificates in {}",
+                self.cert_path.display()
+            )));
+        }
+
+        let mut reader = BufReader::new(File::open(&self.key_path)?);
+        let key = rustls_pemfile::private_key(&mut reader)?
+            .ok_or_else(|| Error::Tls(format!("no private key in {}", self.key_path.display())))?;
+        let key = ring::sign::any_supported_type(&key).map_err(|err| {
+            Error::Tls(format!("invalid key {}: {}", self.key_path.display(), err))
+        })?;
+
+        Ok(CertifiedKey::new(certs, key))
+    }
+
+    /// Latest modification time of the certificate and key files
+    fn modified(&self) -> Option<SystemTime> {
+        let cert = std::fs::metadata(&self.cert_path).and_then(|m| m.modified());
+        let key = std::fs::metadata(&self.key_path).and_then(|m| m.modified());
+        cert.ok().max(key.ok())
+    }
+}
+
+/// Serves the current certificate, swapped out when it's reloaded
+#[derive(Debug)]
+struct CertResolver {
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.current.read().ok().map(|current| current.clone())
+    }
+}
+
+/// Reloads the certificate into the resolver on SIGHUP or when the files change
+/// A failed reload keeps serving the previous certificate
+async fn watch(cfg: TlsConfig, resolver: Weak<CertResolver>) {
+    let mut hangup = hangup_signal();
+    let mut modified = cfg.modified();
+    let mut interval = tokio::time::interval(WATCH_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let latest = cfg.modified();
+                if latest == modified {
+                    if resolver.strong_count() == 0 {
+                        break;
+                    }
+                    continue;
+                }
+                modified = latest;
+                info!("certificate files changed, reloading");
+            }
+            _ = recv_hangup(&mut hangup) => info!("got SIGHUP, reloading certificate"),
+        }
+
+        let resolver = match resolver.upgrade() {
+            Some(resolver) => resolver,
+            None => break,
+        };
+        match cfg.load() {
+            Ok(key) => match resolver.current.write() {
+                Ok(mut current) => {
+                    *current = Arc::new(key);
+                    info!("reloaded certificate {}", cfg.cert_path.display());
+                }
+                Err(err) => error!("couldn't replace certificate: {}", err),
+            },
+            Err(err) => error!(
+                "error reloading certificate, keeping the current one: {}",
+                err
+            ),
+        }
+    }
+
+    debug!("certificate watcher stopped");
+}
+
+#[cfg(unix)]
+type Hangup = Option<tokio::signal::unix::Signal>;
+
+#[cfg(unix)]
+fn hangup_signal() -> Hangup {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    match signal(SignalKind::hangup()) {
+        Ok(hangup) => Some(hangup),
+        Err(err) => {
+            warn!("couldn't listen for SIGHUP: {}", err);
+            None
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn recv_hangup(hangup: &mut Hangup) {
+    match hangup {
+        Some(signal) => {
+            if signal.recv().await.is_none() {
+                *hangup = None;
+            }
+        }
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(not(unix))]
+type Hangup = ();
+
+#[cfg(not(unix))]
+fn hangup_signal() -> Hangup {}
+
+#[cfg(not(unix))]
+async fn recv_hangup(_: &mut Hangup) {
+    std::future::pending().await
+}
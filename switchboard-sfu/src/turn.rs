@@ -0,0 +1,484 @@
+use async_mutex::Mutex;
+use async_trait::async_trait;
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use enclose::enc;
+use log::*;
+use serde::Deserialize;
+use std::any::Any;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+use webrtc::ice_transport::ice_server::RTCIceServer;
+use webrtc::turn::auth::{generate_auth_key, AuthHandler};
+use webrtc::turn::relay::relay_range::RelayAddressGeneratorRanges;
+use webrtc::turn::relay::relay_static::RelayAddressGeneratorStatic;
+use webrtc::turn::relay::RelayAddressGenerator;
+use webrtc::turn::server::config::{ConnConfig, ServerConfig};
+use webrtc::turn::server::Server;
+use webrtc::util::vnet::net::Net;
+use webrtc::util::Conn;
+
+use crate::config::IceServerConfig;
+use crate::{Error, Result};
+
+/// How long channel bindings last without a refresh
+const CHANNEL_BIND_TIMEOUT: Duration = Duration::from_secs(600);
+/// Attempts at finding a free relay port in the relay port range
+const RELAY_PORT_RETRIES: u16 = 10;
+/// Messages read from TCP clients waiting for the TURN server
+const TCP_QUEUE_SIZE: usize = 256;
+const STUN_HEADER_SIZE: usize = 20;
+
+/// Embedded STUN/TURN server, for deployments without coturn
+/// Listening on udp_port and/or tcp_port enables it
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct TurnConfig {
+    /// UDP port of the STUN/TURN listener
+    pub udp_port: Option<u16>,
+    /// TCP port of the TURN listener, for clients that can't use UDP
+    pub tcp_port: Option<u16>,
+    /// IP clients reach the server and its relays at, the first NAT 1:1 IP if unset
+    pub public_ip: Option<IpAddr>,
+    pub realm: String,
+    /// Long-term credentials, handed to clients in the join response
+    pub username: String,
+    pub password: String,
+    /// TURN REST API shared secret, replaces username/password with credentials issued for
+    /// every join
+    pub secret: Option<String>,
+    /// Range of UDP ports relays bind, any port if unset
+    pub relay_port_min: Option<u16>,
+    pub relay_port_max: Option<u16>,
+}
+
+impl Default for TurnConfig {
+    fn default() -> TurnConfig {
+        TurnConfig {
+            udp_port: None,
+            tcp_port: None,
+            public_ip: None,
+            realm: "switchboard".to_owned(),
+            username: String::new(),
+            password: String::new(),
+            secret: None,
+            relay_port_min: None,
+            relay_port_max: None,
+        }
+    }
+}
+
+impl TurnConfig {
+    pub fn enabled(&self) -> bool {
+        self.udp_port.is_some() || self.tcp_port.is_some()
+    }
+}
+
+/// Running TURN server, see TurnServer::start
+pub struct TurnServer {
+    server: Server,
+    ice_server: IceServerConfig,
+}
+
+impl TurnServer {
+    /// Listens on the configured ports, relays are advertised at public_ip
+    pub async fn start(cfg: &TurnConfig, public_ip: IpAddr) -> Result<TurnServer> {
+        let unspecified = match public_ip {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+
+        let mut conn_configs = vec![];
+        let mut urls = vec![];
+        if let Some(port) = cfg.udp_port {
+            let socket = UdpSocket::bind(SocketAddr::new(unspecified, port)).await?;
+            let addr = SocketAddr::new(public_ip, socket.local_addr()?.port());
+            info!("turn server listening on udp {}", socket.local_addr()?);
+
+            urls.push(format!("stun:{}", addr));
+            urls.push(format!("turn:{}?transport=udp", addr));
+            conn_configs.push(ConnConfig {
+                conn: Arc::new(socket),
+                relay_addr_generator: relay_addr_generator(cfg, public_ip, unspecified),
+            });
+        }
+        if let Some(port) = cfg.tcp_port {
+            let conn = TcpConn::bind(SocketAddr::new(unspecified, port)).await?;
+            let addr = SocketAddr::new(public_ip, conn.local_addr.port());
+            info!("turn server listening on tcp {}", conn.local_addr);
+
+            urls.push(format!("turn:{}?transport=tcp", addr));
+            conn_configs.push(ConnConfig {
+                conn: Arc::new(conn),
+                relay_addr_generator: relay_addr_generator(cfg, public_ip, unspecified),
+            });
+        }
+
+        let auth_handler: Arc<dyn AuthHandler + Send + Sync> = match &cfg.secret {
+            Some(secret) => Arc::new(RestAuthHandler {
+                secret: secret.clone(),
+            }),
+            None => Arc::new(StaticAuthHandler {
+                username: cfg.username.clone(),
+                key: generate_auth_key(&cfg.username, &cfg.realm, &cfg.password),
+            }),
+        };
+        let server = Server::new(ServerConfig {
+            conn_configs,
+            realm: cfg.realm.clone(),
+            auth_handler,
+            channel_bind_timeout: CHANNEL_BIND_TIMEOUT,
+            alloc_close_notify: None,
+        })
+        .await
+        .map_err(|err| Error::Turn(err.to_string()))?;
+
+        Ok(TurnServer {
+            server,
+            ice_server: IceServerConfig {
+                urls,
+                username: cfg.username.clone(),
+                credential: cfg.password.clone(),
+                secret: cfg.secret.clone(),
+            },
+        })
+    }
+
+    /// ICE server clients reach this server with
+    pub fn ice_server(&self) -> IceServerConfig {
+        self.ice_server.clone()
+    }
+
+    /// Stops the listeners and closes every allocation
+    pub async fn close(&self) {
+        if let Err(err) = self.server.close().await {
+            error!("error closing turn server: {}", err);
+        }
+    }
+}
+
+fn relay_addr_generator(
+    cfg: &TurnConfig,
+    public_ip: IpAddr,
+    unspecified: IpAddr,
+) -> Box<dyn RelayAddressGenerator + Send + Sync> {
+    let net = Arc::new(Net::new(None));
+    match (cfg.relay_port_min, cfg.relay_port_max) {
+        (Some(min_port), Some(max_port)) => Box::new(RelayAddressGeneratorRanges {
+            relay_address: public_ip,
+            min_port,
+            max_port,
+            max_retries: RELAY_PORT_RETRIES,
+            address: unspecified.to_string(),
+            net,
+        }),
+        _ => Box::new(RelayAddressGeneratorStatic {
+            relay_address: public_ip,
+            address: unspecified.to_string(),
+            net,
+        }),
+    }
+}
+
+/// Accepts the configured long-term credentials
+struct StaticAuthHandler {
+    username: String,
+    key: Vec<u8>,
+}
+
+impl AuthHandler for StaticAuthHandler {
+    fn auth_handle(
+        &self,
+        username: &str,
+        _realm: &str,
+        src_addr: SocketAddr,
+    ) -> std::result::Result<Vec<u8>, webrtc::turn::Error> {
+        if username != self.username {
+            debug!(
+                "turn auth from {} with unknown user {:?}",
+                src_addr, username
+            );
+            return Err(webrtc::turn::Error::ErrNoSuchUser);
+        }
+        Ok(self.key.clone())
+    }
+}
+
+/// Accepts TURN REST credentials of the shared secret until they expire
+struct RestAuthHandler {
+    secret: String,
+}
+
+impl AuthHandler for RestAuthHandler {
+    fn auth_handle(
+        &self,
+        username: &str,
+        realm: &str,
+        src_addr: SocketAddr,
+    ) -> std::result::Result<Vec<u8>, webrtc::turn::Error> {
+        let expiry = username
+            .split(':')
+            .next()
+            .and_then(|expiry| expiry.parse::<u64>().ok())
+            .ok_or(webrtc::turn::Error::ErrNoSuchUser)?;
+        if expiry < unix_time() {
+            debug!(
+                "turn auth from {} with expired user {:?}",
+                src_addr, username
+            );
+            return Err(webrtc::turn::Error::Other(format!(
+                "expired username {}",
+                username
+            )));
+        }
+
+        let password = rest_password(&self.secret, username);
+        Ok(generate_auth_key(username, realm, &password))
+    }
+}
+
+/// TURN REST API credentials for identity, valid for ttl: the username is expiry:identity
+/// (expiry in unix seconds) and the password the base64 HMAC-SHA1 of the username
+pub fn rest_credentials(secret: &str, identity: &str, ttl: Duration) -> (String, String) {
+    let username = format!("{}:{}", unix_time() + ttl.as_secs(), identity);
+    let password = rest_password(secret, &username);
+    (username, password)
+}
+
+fn rest_password(secret: &str, username: &str) -> String {
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret.as_bytes());
+    BASE64_STANDARD.encode(ring::hmac::sign(&key, username.as_bytes()))
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// STUN/TURN servers issued to the peers of every join, servers with a secret get fresh TURN
+/// REST credentials each time
+#[derive(Clone, Default, Debug)]
+pub struct IceServers {
+    servers: Vec<IceServerConfig>,
+    // only handed to clients, eg. the embedded TURN server
+    client_servers: Vec<IceServerConfig>,
+    credential_ttl: Duration,
+}
+
+impl IceServers {
+    pub fn new(servers: Vec<IceServerConfig>, credential_ttl: Duration) -> IceServers {
+        IceServers {
+            servers,
+            client_servers: vec![],
+            credential_ttl,
+        }
+    }
+
+    /// Adds a server used by clients but not by the server side peer connections
+    pub fn with_client_server(mut self, server: IceServerConfig) -> IceServers {
+        self.client_servers.push(server);
+        self
+    }
+
+    /// Issues the servers of one join, for the server side peer connections and for the
+    /// client. Credentials are issued for identity, or a random id without one.
+    pub fn issue(&self, identity: Option<&str>) -> (Vec<RTCIceServer>, Vec<RTCIceServer>) {
+        let identity = match identity {
+            Some(identity) => identity.to_owned(),
+            None => Uuid::new_v4().simple().to_string(),
+        };
+
+        let servers: Vec<RTCIceServer> = self
+            .servers
+            .iter()
+            .map(|server| server.issue(&identity, self.credential_ttl))
+            .collect();
+        let mut client_servers = servers.clone();
+        client_servers.extend(
+            self.client_servers
+                .iter()
+                .map(|server| server.issue(&identity, self.credential_ttl)),
+        );
+
+        (servers, client_servers)
+    }
+}
+
+/// TURN over TCP for the turn crate, which only serves packet connections
+/// Every accepted stream is split into its STUN and ChannelData messages, replies go back on
+/// the stream of their destination address
+struct TcpConn {
+    local_addr: SocketAddr,
+    incoming: Mutex<mpsc::Receiver<(Vec<u8>, SocketAddr)>>,
+    writers: Arc<Mutex<HashMap<SocketAddr, Arc<Mutex<OwnedWriteHalf>>>>>,
+    accept_loop: JoinHandle<()>,
+}
+
+impl TcpConn {
+    async fn bind(addr: SocketAddr) -> io::Result<TcpConn> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let (tx, rx) = mpsc::channel(TCP_QUEUE_SIZE);
+        let writers: Arc<Mutex<HashMap<SocketAddr, Arc<Mutex<OwnedWriteHalf>>>>> =
+            Arc::new(Mutex::new(HashMap::new()));
+
+        let accept_loop = tokio::spawn(enc!( (writers) async move {
+            while let Ok((stream, addr)) = listener.accept().await {
+                debug!("turn tcp connection from {}", addr);
+                let (reader, writer) = stream.into_split();
+                writers.lock().await.insert(addr, Arc::new(Mutex::new(writer)));
+
+                tokio::spawn(enc!( (writers, tx) async move {
+                    if let Err(err) = read_messages(reader, addr, &tx).await {
+                        if err.kind() != io::ErrorKind::UnexpectedEof {
+                            debug!("turn tcp connection from {} failed: {}", addr, err);
+                        }
+                    }
+                    writers.lock().await.remove(&addr);
+                }));
+            }
+        }));
+
+        Ok(TcpConn {
+            local_addr,
+            incoming: Mutex::new(rx),
+            writers,
+            accept_loop,
+        })
+    }
+}
+
+/// Reads the messages of a TURN client stream until it closes
+async fn read_messages(
+    mut reader: OwnedReadHalf,
+    addr: SocketAddr,
+    tx: &mpsc::Sender<(Vec<u8>, SocketAddr)>,
+) -> io::Result<()> {
+    let mut header = [0u8; 4];
+    loop {
+        reader.read_exact(&mut header).await?;
+        let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+        // the first two bits tell STUN messages (0b00) and ChannelData (0b01) apart,
+        // ChannelData is padded to 4 bytes over TCP
+        let remaining = match header[0] >> 6 {
+            0 => STUN_HEADER_SIZE - header.len() + length,
+            1 => (length + 3) & !3,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "not a STUN or ChannelData message",
+                ))
+            }
+        };
+
+        let mut msg = header.to_vec();
+        msg.resize(header.len() + remaining, 0);
+        reader.read_exact(&mut msg[header.len()..]).await?;
+        if tx.send((msg, addr)).await.is_err() {
+            return Ok(());
+        }
+    }
+}
+
+#[async_trait]
+impl Conn for TcpConn {
+    async fn connect(&self, _addr: SocketAddr) -> webrtc::util::Result<()> {
+        Err(io::Error::from(io::ErrorKind::Unsupported).into())
+    }
+
+    async fn recv(&self, _buf: &mut [u8]) -> webrtc::util::Result<usize> {
+        Err(io::Error::from(io::ErrorKind::Unsupported).into())
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> webrtc::util::Result<(usize, SocketAddr)> {
+        let (msg, addr) = self
+            .incoming
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or(webrtc::util::Error::ErrUseClosedNetworkConn)?;
+        let n = msg.len().min(buf.len());
+        buf[..n].copy_from_slice(&msg[..n]);
+        Ok((n, addr))
+    }
+
+    async fn send(&self, _buf: &[u8]) -> webrtc::util::Result<usize> {
+        Err(io::Error::from(io::ErrorKind::Unsupported).into())
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> webrtc::util::Result<usize> {
+        let writer = self.writers.lock().await.get(&target).cloned();
+        let writer = writer.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotConnected,
+                format!("no turn tcp connection from {}", target),
+            )
+        })?;
+        writer.lock().await.write_all(buf).await?;
+        Ok(buf.len())
+    }
+
+    fn local_addr(&self) -> webrtc::util::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    async fn close(&self) -> webrtc::util::Result<()> {
+        self.accept_loop.abort();
+        // dropping the write halves shuts the streams down
+        self.writers.lock().await.clear();
+        Ok(())
+    }
+
+    fn as_any(&self) -> &(dyn Any + Send + Sync) {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rest_password_is_base64_hmac_sha1() {
+        assert_eq!(
+            rest_password("key", "The quick brown fox jumps over the lazy dog"),
+            "3nybhbi3iqa8ino29wqQcBydtNk="
+        );
+    }
+
+    #[test]
+    fn rest_auth_checks_expiry() {
+        let handler = RestAuthHandler {
+            secret: "secret".to_owned(),
+        };
+        let addr = "192.0.2.1:3478".parse().unwrap();
+
+        let (username, password) = rest_credentials("secret", "alice", Duration::from_secs(60));
+        assert!(username.ends_with(":alice"));
+        assert_eq!(
+            handler.auth_handle(&username, "realm", addr).unwrap(),
+            generate_auth_key(&username, "realm", &password)
+        );
+
+        let expired = format!("{}:alice", unix_time() - 1);
+        assert!(handler.auth_handle(&expired, "realm", addr).is_err());
+        assert!(handler.auth_handle("alice", "realm", addr).is_err());
+    }
+}